@@ -1,7 +1,9 @@
-use std::fs::{self, File};
-use std::path::{Path, PathBuf};
-use std::io;
+use std::fs::File;
+use std::path::PathBuf;
+use std::{fmt, io};
 use std::io::prelude::*;
+use rustc_serialize::{Encodable, Encoder};
+use rustc_serialize::hex::FromHex;
 use toml;
 use utils::*;
 
@@ -17,6 +19,7 @@ pub struct ServerConfig {
     pub data_directory: PathBuf,
     pub bind_address: String,
     pub max_connections: usize,
+    pub max_disk_usage: u64,
     pub maintenance_interval: u64,
     pub monitor_interval: u64,
     pub default_queue_config: QueueConfig,
@@ -27,11 +30,59 @@ pub struct QueueConfig {
     pub name: String,
     pub data_directory: PathBuf,
     pub segment_size: u64,
+    pub adaptive_segment: bool,
+    pub min_segment_size: u64,
+    pub max_segment_size: u64,
+    pub max_message_size: u64,
     pub message_timeout: u32,
+    pub redelivery_jitter: u32,
+    pub max_backoff: u32,
+    pub max_retries: u32,
+    pub dead_letter_queue: String,
+    pub read_ahead: u32,
+    pub max_in_flight: u32,
+    pub channel_ttl: u32,
+    pub auto_create_channels: bool,
+    pub sync_interval: u32,
+    pub gc_min_segments: u32,
+    pub max_channel_lag: u64,
     pub retention_period: u32,
     pub hard_retention_period: u32,
     pub retention_size: u64,
     pub hard_retention_size: u64,
+    pub encryption: Option<EncryptionKey>,
+}
+
+/// AES-256 key messages are encrypted with before being written to disk,
+/// it's only kept in memory
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey(Vec<u8>);
+
+impl EncryptionKey {
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey, GenericError> {
+        let key = try!(hex.from_hex());
+        if key.len() != 32 {
+            return Err(format!("Expected a 32 bytes key, got {} bytes", key.len()).into())
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// the key must not show up in the logs or the CONFIG GET output
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl Encodable for EncryptionKey {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str("..")
+    }
 }
 
 
@@ -77,6 +128,9 @@ macro_rules! read_config {
     ($config: expr, $name: expr => int) => {
         read_config!($config, $name, as_integer, "integer")
     };
+    ($config: expr, $name: expr => bool) => {
+        read_config!($config, $name, as_bool, "boolean")
+    };
     ($config: expr, $name: expr => size) => {
         parse_size(read_config!($config, $name, as_str, "size")).
             expect(concat!("Config ", $name, " can't be parsed as size"))
@@ -84,6 +138,14 @@ macro_rules! read_config {
     ($config: expr, $name: expr => duration) => {
         parse_duration(read_config!($config, $name, as_str, "duration")).
             expect(concat!("Config ", $name, " can't be parsed as duration"))
+    };
+    // for keys added later, so older config files still work
+    ($config: expr, $name: expr => $kind: ident, default $default: expr) => {
+        if $config.get($name).is_some() {
+            read_config!($config, $name => $kind)
+        } else {
+            $default
+        }
     }
 }
 
@@ -101,47 +163,83 @@ impl ServerConfig {
         let bind_address = read_config!(config, "bind_address" => str);
         let data_directory = read_config!(config, "data_directory" => str);
         let max_connections = read_config!(config, "max_connections" => int);
+        let max_disk_usage = read_config!(config, "max_disk_usage" => size, default 0);
         let segment_size = read_config!(config, "segment_size" => size);
+        let adaptive_segment = read_config!(config, "adaptive_segment" => bool, default false);
+        let min_segment_size = read_config!(config, "min_segment_size" => size, default 16 * 1024 * 1024);
+        let max_segment_size = read_config!(config, "max_segment_size" => size, default 1024 * 1024 * 1024);
+        let max_message_size = read_config!(config, "max_message_size" => size, default 0);
         let maintenance_interval = read_config!(config, "maintenance_interval" => duration);
         let monitor_interval = read_config!(config, "monitor_interval" => duration);
         let message_timeout = read_config!(config, "message_timeout" => duration);
+        let redelivery_jitter = read_config!(config, "redelivery_jitter" => duration, default 0);
+        let max_backoff = read_config!(config, "max_backoff" => duration, default 0);
+        let sync_interval = read_config!(config, "sync_interval" => duration, default 0);
+        let gc_min_segments = read_config!(config, "gc_min_segments" => int, default 1);
+        let max_channel_lag = read_config!(config, "max_channel_lag" => int, default 0);
+        let max_retries = read_config!(config, "max_retries" => int, default 0);
+        let dead_letter_queue = read_config!(config, "dead_letter_queue" => str, default "");
+        let read_ahead = read_config!(config, "read_ahead" => int, default 0);
+        let max_in_flight = read_config!(config, "max_in_flight" => int, default 0);
+        let channel_ttl = read_config!(config, "channel_ttl" => duration, default 0);
+        let auto_create_channels = read_config!(config, "auto_create_channels" => bool, default false);
         let retention_period = read_config!(config, "retention_period" => duration);
         let hard_retention_period = read_config!(config, "hard_retention_period" => duration);
         let retention_size = read_config!(config, "retention_size" => size);
         let hard_retention_size = read_config!(config, "hard_retention_size" => size);
+        let encryption_key = read_config!(config, "encryption_key" => str, default "");
 
         assert!(segment_size >= 16 * 1024 * 1024 && segment_size <= 1 << 31,
             "segment_size must be between 16MB and 2GB");
+        assert!(min_segment_size >= 1024 * 1024 && min_segment_size <= max_segment_size &&
+            max_segment_size < 1 << 31,
+            "min_segment_size and max_segment_size must be between 1MB and 2GB");
+        let encryption = if encryption_key.is_empty() {
+            None
+        } else {
+            Some(EncryptionKey::from_hex(encryption_key)
+                .expect("Config encryption_key can't be parsed as a 256 bits hex key"))
+        };
+        assert!(encryption.is_none() || cfg!(feature = "encryption"),
+            "encryption_key requires building with --features encryption");
         create_dir_if_not_exist(data_directory).expect("Data directory not acessible");
 
         ServerConfig {
             data_directory: data_directory.into(),
             bind_address: bind_address.into(),
             max_connections: max_connections as usize,
+            max_disk_usage: max_disk_usage,
             maintenance_interval: maintenance_interval,
             monitor_interval: monitor_interval,
             default_queue_config: QueueConfig {
                 name: "".into(),
                 data_directory: "".into(),
                 segment_size: segment_size,
+                adaptive_segment: adaptive_segment,
+                min_segment_size: min_segment_size,
+                max_segment_size: max_segment_size,
+                max_message_size: max_message_size,
                 message_timeout: (message_timeout / 1000) as u32,
+                redelivery_jitter: (redelivery_jitter / 1000) as u32,
+                max_backoff: (max_backoff / 1000) as u32,
+                max_retries: max_retries as u32,
+                dead_letter_queue: dead_letter_queue.into(),
+                read_ahead: read_ahead as u32,
+                max_in_flight: max_in_flight as u32,
+                channel_ttl: (channel_ttl / 1000) as u32,
+                auto_create_channels: auto_create_channels,
+                sync_interval: (sync_interval / 1000) as u32,
+                gc_min_segments: gc_min_segments as u32,
+                max_channel_lag: max_channel_lag as u64,
                 retention_period: (retention_period / 1000) as u32,
                 hard_retention_period : (hard_retention_period / 1000) as u32,
                 retention_size : retention_size,
                 hard_retention_size: hard_retention_size,
+                encryption: encryption,
             }
         }
     }
 
-    pub fn read_queue_configs(self: &ServerConfig) -> Result<Vec<QueueConfig>, GenericError> {
-        debug!("reading queue configurations");
-        let mut queue_configs = Vec::new();
-        for maybe_entry in try!(fs::read_dir(&self.data_directory)) {
-            queue_configs.push(try!(QueueConfig::read(self, &try!(maybe_entry).path())))
-        }
-        Ok(queue_configs)
-    }
-
     pub fn new_queue_config<S: Into<String>>(&self, queue_name: S) -> QueueConfig {
         QueueConfig::new(self, queue_name.into())
     }
@@ -153,16 +251,10 @@ impl QueueConfig {
         QueueConfig {
             name: name,
             data_directory: data_directory,
-            .. server_config.default_queue_config
+            .. server_config.default_queue_config.clone()
         }
     }
 
-    fn read(server_config: &ServerConfig, data_directory: &Path) -> Result<QueueConfig, GenericError> {
-        let name = data_directory.file_name().unwrap().to_string_lossy().into_owned();
-        Ok(Self::new(server_config, name))
-        // TODO: load from a file instead
-    }
-
     fn write(&self) -> io::Result<()> {
         Ok(())
     }
@@ -23,6 +23,8 @@ extern crate rand;
 extern crate tendril;
 extern crate fs2;
 extern crate promising_future;
+#[cfg(feature = "tracing")] extern crate tracing;
+#[cfg(feature = "encryption")] extern crate aes_gcm;
 
 #[macro_use] mod utils;
 mod config;
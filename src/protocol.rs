@@ -55,9 +55,10 @@ impl Value {
             Value::Error(v) =>
                 write!(f, "-{}\r\n", v.as_ref()),
             Value::Message((ticket, message)) => {
+                let body = message.body().expect("sending a message that wasn't decrypted");
                 write!(f, "*3\r\n:{}\r\n:{}\r\n${}\r\n",
-                    message.id(), ticket, message.body().len()).unwrap();
-                f.write_all(message.body()).unwrap();
+                    message.id(), ticket, body.len()).unwrap();
+                f.write_all(body).unwrap();
                 write!(f, "\r\n")
             }
         }.unwrap()
@@ -1,9 +1,13 @@
-use std::sync::Mutex;
-use std::collections::{BTreeMap, BinaryHeap};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
 use std::collections::hash_map::Entry;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::fs::{self, File};
-use std::mem;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::thread;
+use std::{cmp, fmt};
 use rustc_serialize::json;
 
 use atom::*;
@@ -23,7 +27,17 @@ pub enum QueueError {
     ChannelAlreadyExists,
     ChannelNotFound,
     TicketNotFound,
+    QueueDraining,
+    IdOutOfRange,
     EndOfQueue(u64),
+    /// the queue was being deleted when it was last open, opening it finished the deletion
+    WasDeleting,
+    /// the message with the id doesn't match its hash, it won't be sent again
+    CorruptMessage(u64),
+    /// the message with the id was encrypted with another key, it's left in flight
+    DecryptionFailed(u64),
+    /// the message needs the format version, see Queue::upgrade_format
+    FormatUpgradeRequired(u32),
     Backend(QueueBackendError),
 }
 
@@ -33,9 +47,34 @@ impl From<QueueBackendError> for QueueError {
     }
 }
 
+/// Signal sent when the backlog crosses the watermarks, see Queue::set_watermarks
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Backpressure {
+    Pause,
+    Resume,
+}
+
+pub type BackpressureCallback = Arc<Fn(Backpressure) + Send + Sync>;
+
+/// Backlog thresholds, high == 0 disables them
+struct Watermarks {
+    high: u64,
+    low: u64,
+    paused: bool,
+    callback: Option<BackpressureCallback>,
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum ChannelCreation {
+    Created,
+    AlreadyExists,
+}
+
 #[derive(Eq, PartialEq, Debug, Copy, Clone, RustcDecodable, RustcEncodable)]
 pub enum QueueState {
     Ready,
+    // doesn't accept new messages, consumers are unaffected
+    Draining,
     Deleting
 }
 
@@ -46,6 +85,18 @@ pub struct ChannelInfo {
     pub in_flight_count: u32,
 }
 
+/// Cheap per channel counters for monitoring, see Queue::channel_stats
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ChannelStats {
+    pub tail: u64,
+    /// messages in flight, including the ones that timed out but weren't sent again yet
+    pub in_flight_count: usize,
+    pub last_touched: u32,
+    /// messages between the smallest unacknowledged id and the head, as in ChannelStatus,
+    /// so the ones in flight are included
+    pub lag: u64,
+}
+
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 pub struct QueueInfo {
     pub head: u64,
@@ -53,26 +104,95 @@ pub struct QueueInfo {
     pub channels: BTreeMap<String, ChannelInfo>,
     pub segments_count: u32,
     pub disk_usage: u64,
+    pub bytes_produced: u64,
+    pub bytes_delivered: u64,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct ChannelStatus {
+    pub tail: u64,
+    pub in_flight_count: u32,
+    /// messages between the smallest unacknowledged id and the head, as in ChannelStats
+    pub lag: u64,
+}
+
+/// Snapshot for monitoring, see Queue::status_json
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct QueueStatus {
+    pub name: String,
+    pub state: QueueState,
+    pub head: u64,
+    pub tail: u64,
+    pub channels: BTreeMap<String, ChannelStatus>,
+}
+
+#[derive(Debug, RustcDecodable, RustcEncodable)]
+pub struct Health {
+    pub state: QueueState,
+    pub writable: bool,
+    /// age of the oldest message still retained
+    pub oldest_age: Option<u32>,
+    /// number of channels lagging more than the requested threshold
+    pub channels_behind: usize,
+}
+
+/// Aggregated counters for scraping, see Queue::metrics
+#[derive(Debug, Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct QueueMetrics {
+    pub head: u64,
+    pub tail: u64,
+    /// segment files currently in use
+    pub files_count: usize,
+    pub channel_count: usize,
+    /// messages in flight across all channels, including the ones that timed out
+    pub total_in_flight: usize,
+    pub state: QueueState,
+}
+
+/// Where a channel sends messages that exceeded max_retries
+#[derive(Debug, Clone, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub enum DlqTarget {
+    /// another channel in the same queue, note that it also gets new messages as usual
+    Channel(String),
+    /// a sibling queue, the message is copied and pushed to it (see `Queue::forward_dead_letters`)
+    Queue(String),
 }
 
 #[derive(Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
 struct ChannelCheckpoint {
     tail: u64,
     last_touched: u32,
+    dlq: Option<DlqTarget>,
+    auto_ack: Option<bool>,
+    paused: Option<bool>,
+    // (deliver_at, id) of the delayed messages, missing before they were kept,
+    // the tail was held back by them instead
+    delayed: Option<Vec<(u32, u64)>>,
+    // ids dead lettered into this channel and the ones leaving it, also missing before
+    // they were kept, the tail was held back by them instead
+    dead_letters: Option<Vec<u64>>,
+    outgoing: Option<Vec<(DlqTarget, u64)>>,
 }
 
 #[derive(Debug, RustcDecodable, RustcEncodable)]
 struct QueueCheckpoint {
     state: QueueState,
     channels: BTreeMap<String, ChannelCheckpoint>,
+    // missing in the first version
+    version: Option<u32>,
 }
 
 const EXPIRED: u32 = 0;
 
+/// Version of the on disk format written by this build
+pub const FORMAT_VERSION: u32 = 5;
+
 #[derive(Debug)]
 struct InFlightState {
     id: u64,
     expiration: u32,
+    // number of times it expired and was sent again
+    retry: u32,
 }
 
 #[derive(Debug)]
@@ -84,14 +204,50 @@ pub struct Channel {
     in_flight_map: LinkedHashMap<i64, InFlightState>,
     // keeps track of the smallest in flight ids and their tickets (possibly expired)
     in_flight_heap: BinaryHeap<(Rev<u64>, i64)>,
+    // keeps track of the earliest expirations, their ids and tickets (possibly stale)
+    expiration_heap: BinaryHeap<(Rev<u32>, Rev<u64>, i64)>,
+    // tickets taken from expiration_heap once expired, in expiration order (possibly stale)
+    timed_out: VecDeque<i64>,
+    // ids routed here from other channels, delivered before the tail
+    dead_letters: VecDeque<u64>,
+    // ids that exceeded max_retries, kept until they're handed to their target
+    outgoing: VecDeque<(DlqTarget, u64)>,
+    // messages released with a nack, sent again right away
+    nacked: VecDeque<InFlightState>,
+    // messages past the tail that can't be delivered yet, by delivery timestamp
+    delayed: BinaryHeap<(Rev<u32>, Rev<u64>)>,
+    // messages past the tail pushed with a priority, highest first (possibly stale)
+    priority: BinaryHeap<(u8, Rev<u64>)>,
+    // messages past the tail already sent because of their priority, skipped by the tail
+    sent_ahead: BTreeSet<u64>,
+    // messages following the tail, only valid while the channel reads sequentially
+    read_ahead: Option<ReadAhead>,
+    dlq: Option<DlqTarget>,
+    // messages are considered acked as soon as they're sent, they're never sent again
+    auto_ack: bool,
+    // nothing is sent while paused, in flight messages can still be acked
+    paused: bool,
 }
 
+/// Cheap xorshift generator used to spread message timeouts
+#[derive(Debug)]
+struct JitterRng(u64);
+
 #[derive(Debug)]
 struct InnerQueue {
     config: QueueConfig,
     backend: QueueBackend,
     channels: HashMap<Atom, Mutex<Channel>>,
     state: QueueState,
+    format_version: u32,
+    jitter_rng: Mutex<JitterRng>,
+    // payload bytes pushed and sent to consumers (redeliveries included) since startup
+    bytes_produced: AtomicUsize,
+    bytes_delivered: AtomicUsize,
+    watermarks: Mutex<Watermarks>,
+    // notified when a channel may have become drained, see Queue::wait_empty
+    // the counter is increased on every notification so none is missed
+    drained: Arc<(Mutex<u64>, Condvar)>,
 }
 
 pub struct Queue {
@@ -104,12 +260,55 @@ unsafe impl Send for Queue {}
 unsafe impl Sync for Queue {}
 
 impl Queue {
-    pub fn new(config: QueueConfig, recover: bool) -> Queue {
-        Queue{
+    /// Opens the queue, recovering its state from disk if requested,
+    /// a queue found being deleted is removed instead and WasDeleting is returned
+    pub fn new(config: QueueConfig, recover: bool) -> QueueResult<Queue> {
+        Ok(Queue{
             name: config.name.clone(),
-            inner: TristateLock::new(InnerQueue::new(config, recover)),
+            inner: TristateLock::new(try!(InnerQueue::new(config, recover))),
             maintenance_mutex: Mutex::new(()),
+        })
+    }
+
+    /// Opens every queue found in the server data directory, a directory is a queue
+    /// if it holds a queue checkpoint. Queues with an unreadable checkpoint are Err
+    pub fn discover(server_config: &ServerConfig) -> Vec<Result<Queue, ()>> {
+        let entries = match fs::read_dir(&server_config.data_directory) {
+            Ok(entries) => entries,
+            Err(error) => {
+                warn!("error reading data directory {:?}: {}", server_config.data_directory, error);
+                return Vec::new()
+            }
+        };
+        let mut queues = Vec::new();
+        for maybe_entry in entries {
+            let path = match maybe_entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            let checkpoint_path = path.join(QUEUE_CHECKPOINT_FILE);
+            if !path.is_dir() || !checkpoint_path.is_file() {
+                debug!("skipping {:?}, no queue checkpoint found", path);
+                continue
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let mut contents = String::new();
+            let parsed = File::open(&checkpoint_path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|e| e.to_string())
+                .and_then(|_| json::decode::<QueueCheckpoint>(&contents).map_err(|e| e.to_string()));
+            if let Err(error) = parsed {
+                warn!("[{}] error parsing checkpoint information: {}", name, error);
+                queues.push(Err(()));
+                continue
+            }
+            match Queue::new(server_config.new_queue_config(name), true) {
+                Ok(q) => queues.push(Ok(q)),
+                Err(QueueError::WasDeleting) => info!("finished deleting queue at {:?}", path),
+                Err(_) => queues.push(Err(())),
+            }
         }
+        queues
     }
 
     pub fn name(&self) -> &str {
@@ -126,10 +325,73 @@ impl Queue {
         locked_queue.backend.set_config(config);
     }
 
+    /// Shortcut to change only the message timeout,
+    /// the new value is used by new gets while current in flight messages are unaffected
+    pub fn set_message_timeout(&self, message_timeout: u32) {
+        let mut locked_queue = self.inner.lock();
+        locked_queue.config.message_timeout = message_timeout;
+        let config = locked_queue.config.clone();
+        locked_queue.backend.set_config(config);
+    }
+
     pub fn create_channel(&self, channel_name: &str, clock: u32) -> QueueResult<()> {
         self.inner.lock().create_channel(channel_name, clock)
     }
 
+    /// Create a channel with at most once delivery, gets send the messages without tracking them
+    /// so no ack is needed (the returned tickets can't be acked)
+    pub fn create_auto_ack_channel(&self, channel_name: &str, clock: u32) -> QueueResult<()> {
+        let mut locked_queue = self.inner.lock();
+        let head = locked_queue.backend.head();
+        locked_queue.create_channel_at(channel_name, head, true, clock)
+    }
+
+    /// Same as create_channel but an existing channel isn't an error
+    pub fn ensure_channel(&self, channel_name: &str, clock: u32) -> QueueResult<ChannelCreation> {
+        match self.create_channel(channel_name, clock) {
+            Ok(()) => Ok(ChannelCreation::Created),
+            Err(QueueError::ChannelAlreadyExists) => Ok(ChannelCreation::AlreadyExists),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Whether all messages in the channel were sent and acknowledged
+    pub fn channel_is_drained(&self, channel_name: &str) -> QueueResult<bool> {
+        self.inner.read().channel_is_drained(channel_name)
+    }
+
+    /// Blocks until all channels are drained, returns false if it timed out
+    pub fn wait_empty(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let drained = self.inner.read().drained.clone();
+        let &(ref notifications, ref condvar) = &*drained;
+        loop {
+            // the queue can't be locked while holding the counter, ack does the opposite
+            let seen = *notifications.lock().unwrap();
+            if self.inner.read().is_drained() {
+                return true
+            }
+            let mut guard = notifications.lock().unwrap();
+            while *guard == seen {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false
+                }
+                guard = condvar.wait_timeout(guard, deadline - now).unwrap().0;
+            }
+        }
+    }
+
+    pub fn channel_exists(&self, channel_name: &str) -> bool {
+        self.inner.read().channels.contains_key(channel_name)
+    }
+
+    /// Create a new channel starting at from_id so it gets the messages from there onwards again,
+    /// from_id must be between the queue tail and head
+    pub fn replay_to_channel(&self, channel_name: &str, from_id: u64, clock: u32) -> QueueResult<()> {
+        self.inner.lock().replay_to_channel(channel_name, from_id, clock)
+    }
+
     pub fn delete_channel(&self, channel_name: &str) -> QueueResult<()> {
         self.inner.lock().delete_channel(channel_name)
     }
@@ -138,18 +400,221 @@ impl Queue {
         self.inner.read().purge_channel(channel_name, clock)
     }
 
+    /// Moves the channel tail to the given id, to replay or skip messages,
+    /// unlike seek_channel_to_id the id must be retained and the messages
+    /// in flight are dropped, so they won't be delivered again
+    pub fn seek_channel(&self, channel_name: &str, id: u64, clock: u32) -> QueueResult<()> {
+        self.inner.lock().seek_channel(channel_name, id, clock)
+    }
+
+    /// Deletes the channels idle for more than channel_ttl and returns their names,
+    /// their in flight messages are dropped
+    pub fn expire_idle_channels(&self, clock: u32) -> Vec<String> {
+        if self.inner.read().idle_channels(clock).is_empty() {
+            return Vec::new()
+        }
+        self.inner.lock().expire_idle_channels(clock)
+    }
+
+    /// Merge a channel into another, the resulting channel starts from the
+    /// smallest unacknowledged message of both and the merged channel is deleted
+    pub fn merge_channel(&self, into: &str, from: &str, clock: u32) -> QueueResult<()> {
+        self.inner.lock().merge_channel(into, from, clock)
+    }
+
     pub fn iter_channels<F: Fn(&Atom, &Channel)>(&self, clock: u32, cb: F) {
         self.inner.read().iter_channels(clock, cb)
     }
 
+    /// Marks the expired in flight messages of the channel as available again,
+    /// without waiting for a get, and returns how many are available
+    /// Note: maintenance does this for all channels
+    pub fn sweep_expired(&self, channel_name: &str, clock: u32) -> QueueResult<usize> {
+        self.inner.read().sweep_expired(channel_name, clock)
+    }
+
+    /// Releases the message of the ticket so it's sent again by the next get,
+    /// this counts as a retry
+    pub fn nack(&self, channel_name: &str, ticket: i64, clock: u32) -> QueueResult<()> {
+        self.inner.read().nack(channel_name, ticket, clock)
+    }
+
+    /// Extends the deadline of the message of the ticket to timeout seconds from now,
+    /// for consumers that need longer than the message timeout to process it
+    pub fn touch(&self, channel_name: &str, ticket: i64, timeout: u32, clock: u32) -> QueueResult<()> {
+        self.inner.read().touch(channel_name, ticket, timeout, clock)
+    }
+
+    /// Number of times the message of the ticket timed out or was nacked and was sent again
+    pub fn retry_count(&self, channel_name: &str, ticket: i64) -> QueueResult<u32> {
+        self.inner.read().retry_count(channel_name, ticket)
+    }
+
+    /// Route messages of the channel exceeding max_retries to target instead of sending them again
+    pub fn set_channel_dlq(&self, channel_name: &str, target: Option<DlqTarget>) -> QueueResult<()> {
+        self.inner.read().set_channel_dlq(channel_name, target)
+    }
+
+    /// Stops sending messages to the channel, gets return EndOfQueue as if it was empty
+    /// but the in flight messages can still be acked, the channel isn't expired meanwhile
+    pub fn pause_channel(&self, channel_name: &str) -> QueueResult<()> {
+        self.inner.read().set_channel_paused(channel_name, true)
+    }
+
+    pub fn resume_channel(&self, channel_name: &str) -> QueueResult<()> {
+        self.inner.read().set_channel_paused(channel_name, false)
+    }
+
+    /// Calls push with the queue name and body of each message dead lettered to other queues,
+    /// it's up to push to add them to the respective queues.
+    /// They're checkpointed with their channel until push returns, so a crash doesn't lose them
+    pub fn forward_dead_letters<F: FnMut(&str, &[u8])>(&self, mut push: F) {
+        let outgoing = self.inner.read().outgoing_dead_letters();
+        for &(_, ref queue_name, _, ref body) in &outgoing {
+            if let Some(ref body) = *body {
+                push(queue_name, body);
+            }
+        }
+        self.inner.read().remove_outgoing_dead_letters(outgoing);
+    }
+
     /// get access is suposed to be thread-safe, even while writing
+    /// Messages are delivered in push order, one that isn't acked is only sent again
+    /// after it times out, before any new message
     pub fn get(&self, channel_name: &str, clock: u32) -> QueueResult<(i64, Message)> {
-        self.inner.read().get(channel_name, clock)
+        let timeout = self.inner.read().config.message_timeout;
+        self.get_with_timeout(channel_name, timeout, clock)
+    }
+
+    /// Same as get but the message times out after timeout seconds instead of message_timeout
+    pub fn get_with_timeout(&self, channel_name: &str, timeout: u32, clock: u32)
+            -> QueueResult<(i64, Message)> {
+        let result = self.inner.read().get(channel_name, timeout, clock);
+        match result {
+            Err(QueueError::ChannelNotFound) if self.inner.read().config.auto_create_channels => {
+                // another get may have created it
+                try!(self.ensure_channel(channel_name, clock));
+                self.inner.read().get(channel_name, timeout, clock)
+            }
+            result => result
+        }
+    }
+
+    /// Returns the next new message of the channel without getting it,
+    /// timed out and dead lettered messages aren't considered
+    pub fn peek(&self, channel_name: &str, clock: u32) -> QueueResult<Message> {
+        self.inner.read().peek(channel_name, clock)
+    }
+
+    /// The message with the given id, IdOutOfRange if it isn't retained, channels are unaffected
+    pub fn get_by_id(&self, id: u64) -> QueueResult<Message> {
+        self.inner.read().get_by_id(id)
+    }
+
+    /// Checks the hash of every retained message, returns the ids of the corrupt ones
+    /// Note: reads all the data, it's meant to be used after recovering from a crash
+    pub fn verify_integrity(&self) -> Result<(), Vec<u64>> {
+        self.inner.read().backend.verify_integrity()
+    }
+
+    /// Gets up to count messages taking the channel lock only once,
+    /// returns EndOfQueue if there's none available
+    pub fn get_batch(&self, channel_name: &str, count: usize, clock: u32) -> QueueResult<Vec<(i64, Message)>> {
+        self.inner.read().get_batch(channel_name, &[], count, clock)
+    }
+
+    /// Acks the tickets and gets up to count new messages in one go, for consumers
+    /// pipelining their acks. Tickets that can't be acked are ignored
+    pub fn get_batch_ack(&self, channel_name: &str, ack_tickets: &[i64], count: usize, clock: u32)
+            -> QueueResult<Vec<(i64, Message)>> {
+        self.inner.read().get_batch_ack(channel_name, ack_tickets, count, clock)
+    }
+
+    /// Gets all messages currently available in the channel, acking them if requested
+    /// Note: messages pushed concurrently may or may not be included
+    pub fn drain_channel(&self, channel_name: &str, ack: bool, clock: u32) -> QueueResult<Vec<Message>> {
+        let head = self.inner.read().backend.head();
+        let mut messages = Vec::new();
+        loop {
+            match self.get(channel_name, clock) {
+                Ok((ticket, message)) => {
+                    if ack {
+                        try!(self.ack(channel_name, ticket, clock));
+                    }
+                    let past_head = message.id() >= head;
+                    messages.push(message);
+                    if past_head {
+                        break
+                    }
+                }
+                Err(QueueError::EndOfQueue(_)) => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Passes up to max messages to the callback, acking the ones it returns Ok for,
+    /// the others are left in flight to be sent again. Returns how many were acked.
+    pub fn consume_with<F>(&self, channel_name: &str, max: usize, clock: u32, mut f: F) -> QueueResult<usize>
+            where F: FnMut(&Message) -> Result<(), ()> {
+        let mut consumed = 0;
+        for _ in 0..max {
+            let (ticket, message) = match self.get(channel_name, clock) {
+                Ok(result) => result,
+                Err(QueueError::EndOfQueue(_)) => break,
+                Err(error) => return Err(error),
+            };
+            if f(&message).is_ok() {
+                try!(self.ack(channel_name, ticket, clock));
+                consumed += 1;
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// Same as get but the body can be read incrementally straight from the segment
+    pub fn get_reader(&self, channel_name: &str, clock: u32) -> QueueResult<(i64, io::Cursor<Message>)> {
+        let (ticket, message) = try!(self.get(channel_name, clock));
+        Ok((ticket, io::Cursor::new(message)))
     }
 
     /// all calls are serialized internally
     pub fn push(&self, message: &[u8], clock: u32) -> QueueResult<u64> {
-        self.inner.write().push(message, clock)
+        self.inner.write().push(message, None, None, clock)
+    }
+
+    /// Same as push but the message is never delivered from expires_at onwards,
+    /// even if no channel got it yet
+    /// Note: older queues have their format upgraded first
+    pub fn push_expiring(&self, message: &[u8], expires_at: u32, clock: u32) -> QueueResult<u64> {
+        self.inner.write().push(message, Some(expires_at), None, clock)
+    }
+
+    /// Same as push but the message isn't delivered before deliver_at,
+    /// channels get the messages after it in the meantime
+    /// Note: older queues have their format upgraded first
+    pub fn push_delayed(&self, message: &[u8], deliver_at: u32, clock: u32) -> QueueResult<u64> {
+        self.inner.write().push(message, None, Some(deliver_at), clock)
+    }
+
+    /// Same as push but channels send the message before the ones with a lower priority
+    /// still waiting to be sent, a priority of 0 is the same as push
+    /// Note: priorities aren't persisted, after a restart messages are sent in order
+    pub fn push_priority(&self, message: &[u8], priority: u8, clock: u32) -> QueueResult<u64> {
+        self.inner.write().push_priority(message, priority, clock)
+    }
+
+    /// Sync the data being written to disk if sync_interval elapsed since the last time,
+    /// returns whether it synced
+    pub fn sync_if_due(&self, clock: u32) -> io::Result<bool> {
+        self.inner.write().backend.sync_if_due(clock)
+    }
+
+    /// Durably write all messages pushed so far and the channels state (a full checkpoint),
+    /// meant for clean shutdowns as, unlike dropping the queue, errors are returned
+    pub fn flush(&self) -> io::Result<()> {
+        self.checkpoint_now(true)
     }
 
     /// all calls are serialized internally
@@ -157,23 +622,173 @@ impl Queue {
         self.inner.write().push_many(messages, clock)
     }
 
+    /// Same as push_many but a message failing doesn't stop the others from being pushed,
+    /// the results are in the same order as messages
+    pub fn push_each(&self, messages: &[&[u8]], clock: u32) -> Vec<QueueResult<u64>> {
+        self.inner.write().push_each(messages, clock)
+    }
+
     /// ack access is suposed to be thread-safe, even while writing
     pub fn ack(&self, channel_name: &str, ticket: i64, clock: u32) -> QueueResult<()> {
         self.inner.read().ack(channel_name, ticket, clock)
     }
 
-    pub fn purge(&self) {
+    /// Discard all messages, returns how many were discarded
+    pub fn purge(&self) -> u64 {
         self.inner.lock().purge()
     }
 
+    /// Drop all messages with ids smaller than the specified id,
+    /// channels tails are moved forward accordingly
+    pub fn truncate_to(&self, id: u64) {
+        self.inner.lock().truncate_to(id)
+    }
+
     pub fn info(&self, clock: u32) -> QueueInfo {
         self.inner.read().info(clock)
     }
 
-    pub fn delete(&self) {
+    /// Whether enough data was consumed by all channels for gc to reclaim
+    /// at least gc_min_segments, disregarding retention
+    pub fn gc_advisable(&self) -> bool {
+        self.inner.read().gc_advisable()
+    }
+
+    /// A cheap snapshot of the queue health, channels are considered behind
+    /// if they are more than lag_threshold messages away from the head
+    pub fn health(&self, lag_threshold: u64, clock: u32) -> Health {
+        self.inner.read().health(lag_threshold, clock)
+    }
+
+    /// Queue wide counters, only reads what's already tracked so it's cheap to call often
+    pub fn metrics(&self) -> QueueMetrics {
+        self.inner.read().metrics()
+    }
+
+    /// A QueueStatus encoded as json, all values are read under the same lock
+    pub fn status_json(&self, clock: u32) -> String {
+        json::encode(&self.inner.read().status(clock)).unwrap()
+    }
+
+    /// Age of the oldest message not yet acknowledged by the channel, 0 if it's caught up
+    pub fn channel_lag_secs(&self, channel_name: &str, clock: u32) -> QueueResult<u32> {
+        let inner = self.inner.read();
+        if let Some(channel) = inner.channels.get(channel_name) {
+            Ok(inner.channel_lag_secs(&*channel.lock().unwrap(), clock))
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    pub fn channel_stats(&self, channel_name: &str) -> QueueResult<ChannelStats> {
+        let inner = self.inner.read();
+        if let Some(channel) = inner.channels.get(channel_name) {
+            Ok(channel.lock().unwrap().stats(inner.backend.head()))
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    pub fn all_channel_stats(&self) -> BTreeMap<String, ChannelStats> {
+        let inner = self.inner.read();
+        let head = inner.backend.head();
+        inner.channels.iter()
+            .map(|(channel_name, channel)| (String::from(&channel_name[..]), channel.lock().unwrap().stats(head)))
+            .collect()
+    }
+
+    /// Channels lagging more than lag_threshold_secs (see channel_lag_secs) and their lag, worst first
+    pub fn channels_behind(&self, lag_threshold_secs: u32, clock: u32) -> Vec<(String, u32)> {
+        let inner = self.inner.read();
+        let mut behind: Vec<_> = inner.channels.iter()
+            .map(|(channel_name, channel)| {
+                let lag = inner.channel_lag_secs(&*channel.lock().unwrap(), clock);
+                (String::from(&channel_name[..]), lag)
+            })
+            .filter(|&(_, lag)| lag > lag_threshold_secs)
+            .collect();
+        behind.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        behind
+    }
+
+    /// When draining the queue rejects new messages
+    pub fn set_draining(&self, draining: bool) {
+        let new_state = if draining { QueueState::Draining } else { QueueState::Ready };
+        self.inner.lock().set_state(new_state)
+    }
+
+    /// The backpressure callback is called with Pause once the backlog (head - smallest tail)
+    /// reaches high and with Resume once it gets back to low or less, high == 0 disables it
+    pub fn set_watermarks(&self, high: u64, low: u64) {
+        assert!(low <= high);
+        let inner = self.inner.read();
+        let mut watermarks = inner.watermarks.lock().unwrap();
+        watermarks.high = high;
+        watermarks.low = low;
+        watermarks.paused = false;
+    }
+
+    /// Note: it's called from push and maintenance with the queue locked,
+    /// so it must not call back into the queue
+    pub fn set_backpressure_callback(&self, callback: Option<BackpressureCallback>) {
+        self.inner.read().watermarks.lock().unwrap().callback = callback;
+    }
+
+    /// Copies the current state of the queue into new_dir and opens it as an independent queue
+    /// named new_name, new_dir must not exist.
+    /// Note: pushes are blocked while the data is copied
+    pub fn fork(&self, new_name: &str, new_dir: &Path) -> io::Result<Queue> {
+        let mut config = self.config_cloned();
+        config.name = new_name.into();
+        config.data_directory = new_dir.into();
+        try!(self.inner.lock().snapshot_to(new_dir));
+        Queue::new(config, true).map_err(|error| io::Error::new(io::ErrorKind::Other,
+            format!("error opening the forked queue: {:?}", error)))
+    }
+
+    /// Moves the queue data into new_dir, which must not exist, and names it new_name,
+    /// channels are kept as they are. Waits for outstanding messages to be dropped
+    pub fn rename(&mut self, new_name: &str, new_dir: &Path) -> io::Result<()> {
+        try!(self.inner.lock().rename(new_name, new_dir));
+        self.name = new_name.into();
+        Ok(())
+    }
+
+    /// Checkpoints and releases all resources held by the queue, including open files,
+    /// so it can be safely reopened right away. Fails if messages gotten from it are still alive
+    pub fn close(self) -> io::Result<()> {
+        self.inner.into_inner().close()
+    }
+
+    /// Deletes the queue data, returns how many messages were discarded
+    pub fn delete(&self) -> u64 {
         self.inner.lock().delete()
     }
 
+    /// Limit the disk space used by this and other queues sharing the quota,
+    /// pushes needing a new segment fail once it's exhausted
+    pub fn set_disk_quota(&self, disk_quota: Arc<DiskQuota>) {
+        self.inner.write().backend.set_disk_quota(disk_quota)
+    }
+
+    /// The latest internal errors, like failures writing checkpoints or corrupt data found on recovery
+    pub fn recent_errors(&self) -> Vec<QueueErrorRecord> {
+        self.inner.read().backend.recent_errors().to_vec()
+    }
+
+    /// Version of the on disk format, older versions are still readable
+    pub fn format_version(&self) -> u32 {
+        self.inner.read().format_version
+    }
+
+    /// Moves the on disk data to the current format version, meant to be run once by an operator,
+    /// it's a no-op if already current and newer versions are refused.
+    /// Until then messages older versions can't read are rejected with FormatUpgradeRequired.
+    /// Older builds don't check the version, so they must not open the queue afterwards
+    pub fn upgrade_format(&self) -> io::Result<()> {
+        self.inner.lock().upgrade_format()
+    }
+
     /// Set the channel tail to the specified id
     pub fn seek_channel_to_id(&self, channel_name: &str, id: u64, clock: u32) -> QueueResult<()> {
         self.inner.read().seek_channel_to_id(channel_name, id, clock)
@@ -184,43 +799,147 @@ impl Queue {
         self.inner.read().seek_channel_to_timestamp(channel_name, timestamp, clock)
     }
 
-    #[allow(mutable_transmutes)]
+    /// Same as checkpoint but errors are returned instead of only being logged
+    pub fn checkpoint_now(&self, full: bool) -> io::Result<()> {
+        let maintenance_lock = self.maintenance_mutex.lock().unwrap();
+        let result = self.inner.read().try_checkpoint(full);
+        drop(maintenance_lock);
+        result
+    }
+
     pub fn checkpoint(&self, full: bool) {
         let maintenance_lock = self.maintenance_mutex.lock().unwrap();
-        let inner: &mut InnerQueue = unsafe { mem::transmute(&*self.inner.read()) };
-        inner.checkpoint(full);
+        self.inner.read().checkpoint(full);
         drop(maintenance_lock)
     }
 
-    #[allow(mutable_transmutes)]
+    /// Spawns a thread doing a checkpoint every interval, independently of the maintenance,
+    /// it only holds a weak reference and stops shortly after the queue is dropped or deleted
+    pub fn start_checkpoint_loop(queue: &Arc<Queue>, interval: Duration) -> thread::JoinHandle<()> {
+        let weak_queue = Arc::downgrade(queue);
+        let poll_interval = cmp::min(interval, Duration::from_millis(100));
+        thread::spawn(move || {
+            let mut next_checkpoint = Instant::now() + interval;
+            loop {
+                thread::sleep(poll_interval);
+                let queue = match weak_queue.upgrade() {
+                    Some(queue) => queue,
+                    None => break,
+                };
+                if queue.inner.read().state == QueueState::Deleting {
+                    break
+                }
+                if Instant::now() >= next_checkpoint {
+                    queue.checkpoint(false);
+                    next_checkpoint = Instant::now() + interval;
+                }
+            }
+        })
+    }
+
     pub fn maintenance(&self, clock: u32) {
         let maintenance_lock = self.maintenance_mutex.lock().unwrap();
-        let inner: &mut InnerQueue = unsafe { mem::transmute(&*self.inner.read()) };
-        inner.maintenance(clock);
+        self.inner.read().maintenance(clock);
         drop(maintenance_lock);
     }
 }
 
 impl Channel {
     fn real_tail(&self) -> u64 {
-        if let Some(&(Rev(tail), _)) = self.in_flight_heap.peek() {
-            debug_assert!(tail < self.tail);
-            tail
+        let tail = self.checkpoint_tail();
+        let tail = self.delayed.iter().fold(tail, |a, &(_, Rev(id))| cmp::min(a, id));
+        let tail = self.dead_letters.iter().fold(tail, |a, &b| cmp::min(a, b));
+        self.outgoing.iter().fold(tail, |a, &(_, b)| cmp::min(a, b))
+    }
+
+    /// Same as real_tail but ignoring the delayed and dead lettered messages,
+    /// which are checkpointed apart
+    fn checkpoint_tail(&self) -> u64 {
+        let tail = if let Some(&(Rev(tail), _)) = self.in_flight_heap.peek() {
+            debug_assert!(tail < self.tail || self.sent_ahead.contains(&tail));
+            cmp::min(tail, self.tail)
         } else {
             self.tail
+        };
+        self.nacked.iter().fold(tail, |a, b| cmp::min(a, b.id))
+    }
+
+    fn is_drained(&self, head: u64) -> bool {
+        self.tail >= head && self.in_flight_map.is_empty() && self.dead_letters.is_empty() &&
+            self.nacked.is_empty() && self.delayed.is_empty()
+    }
+
+    fn stats(&self, head: u64) -> ChannelStats {
+        ChannelStats {
+            tail: self.tail,
+            in_flight_count: self.in_flight_map.len(),
+            last_touched: self.last_touched,
+            lag: head.saturating_sub(self.real_tail()),
+        }
+    }
+
+    fn insert_in_flight(&mut self, ticket: i64, state: InFlightState) {
+        self.in_flight_heap.push((Rev(state.id), ticket));
+        self.expiration_heap.push((Rev(state.expiration), Rev(state.id), ticket));
+        self.in_flight_map.insert(ticket, state);
+    }
+
+    /// remove and return the in flight message that timed out the earliest, if any
+    fn pop_timed_out(&mut self, clock: u32) -> Option<InFlightState> {
+        self.update_state(clock);
+        while let Some(ticket) = self.timed_out.pop_front() {
+            // the ticket is stale if it was dropped since, expired ones can't be touched
+            match self.in_flight_map.get(&ticket) {
+                Some(state) if state.expiration == EXPIRED => (),
+                _ => continue,
+            }
+            self.expired_count -= 1;
+            return self.in_flight_map.remove(&ticket)
+        }
+        None
+    }
+
+    /// drops the timed out messages that expired since, they won't be sent again,
+    /// returns how many were dropped
+    fn drop_expired_in_flight(&mut self, backend: &QueueBackend, clock: u32) -> usize {
+        let dead_tickets: Vec<i64> = self.in_flight_map.iter()
+            .filter(|&(_, state)| state.expiration == EXPIRED || clock >= state.expiration)
+            .filter(|&(_, state)| backend.get(state.id)
+                .map_or(true, |m| m.id() != state.id || m.is_expired(clock)))
+            .map(|(&ticket, _)| ticket)
+            .collect();
+        for ticket in &dead_tickets {
+            let state = self.in_flight_map.remove(ticket).unwrap();
+            if state.expiration == EXPIRED {
+                self.expired_count -= 1;
+            }
+        }
+        self.pop_stale_heap();
+        dead_tickets.len()
+    }
+
+    fn pop_stale_heap(&mut self) {
+        while self.in_flight_heap
+                .peek()
+                .map_or(false, |&(_, ticket)| !self.in_flight_map.contains_key(&ticket)) {
+            self.in_flight_heap.pop();
         }
     }
 
     fn update_state(&mut self, clock: u32) {
-        // first, adjust expired_count accordingly
-        // FIXME: may be expensive
-        for (_, state) in self.in_flight_map.iter_mut().skip(self.expired_count as usize) {
-            if clock >= state.expiration {
-                self.expired_count += 1;
-                state.expiration = EXPIRED;
-            } else {
+        // mark the messages that timed out since, earliest first, and adjust expired_count
+        while let Some(&(Rev(expiration), _, ticket)) = self.expiration_heap.peek() {
+            if clock < expiration {
                 break
             }
+            self.expiration_heap.pop();
+            // the entry is stale if the ticket is gone or was touched since
+            match self.in_flight_map.get_mut(&ticket) {
+                Some(state) if state.expiration == expiration => state.expiration = EXPIRED,
+                _ => continue,
+            }
+            self.expired_count += 1;
+            self.timed_out.push_back(ticket);
         }
     }
 
@@ -229,20 +948,76 @@ impl Channel {
         self.in_flight_map.len() as u32 - self.expired_count
     }
 
+    /// move the tail, the read ahead is discarded as it no longer follows it
+    fn seek(&mut self, new_tail: u64) {
+        if new_tail < self.tail {
+            // replayed messages are sent again
+            self.sent_ahead.clear();
+        } else {
+            self.sent_ahead = self.sent_ahead.split_off(&new_tail);
+        }
+        self.tail = new_tail;
+        self.read_ahead = None;
+    }
+
     fn purge(&mut self, new_tail: u64) {
         self.in_flight_heap.clear();
+        self.expiration_heap.clear();
+        self.timed_out.clear();
         self.in_flight_map.clear();
+        self.dead_letters.clear();
+        self.outgoing.clear();
+        self.nacked.clear();
+        self.delayed.clear();
+        self.priority.clear();
         self.expired_count = 0;
-        self.tail = new_tail;
+        self.seek(new_tail);
+    }
+
+    fn truncate(&mut self, new_tail: u64) {
+        if self.tail < new_tail {
+            self.seek(new_tail);
+        }
+        // in flight messages that are gone can't be acked or delivered again
+        let dead_tickets: Vec<i64> = self.in_flight_map.iter()
+            .filter(|&(_, state)| state.id < new_tail)
+            .map(|(&ticket, _)| ticket)
+            .collect();
+        for ticket in dead_tickets {
+            let state = self.in_flight_map.remove(&ticket).unwrap();
+            if state.expiration == EXPIRED {
+                self.expired_count -= 1;
+            }
+        }
+        self.dead_letters.retain(|&id| id >= new_tail);
+        self.outgoing.retain(|&(_, id)| id >= new_tail);
+        self.nacked.retain(|state| state.id >= new_tail);
+        self.delayed = self.delayed.drain().filter(|&(_, Rev(id))| id >= new_tail).collect();
+        self.pop_stale_heap();
     }
 
     pub fn messages_available(&self) -> u32 {
-        self.expired_count
+        self.expired_count + self.nacked.len() as u32
+    }
+}
+
+impl JitterRng {
+    fn new(seed: u64) -> JitterRng {
+        // xorshift state can't be zero
+        JitterRng(if seed == 0 { 0x9E3779B97F4A7C15 } else { seed })
+    }
+
+    /// returns a number in the [0, max] range
+    fn next(&mut self, max: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % (max as u64 + 1)) as u32
     }
 }
 
 impl InnerQueue {
-    pub fn new(config: QueueConfig, recover: bool) -> InnerQueue {
+    pub fn new(config: QueueConfig, recover: bool) -> QueueResult<InnerQueue> {
         if ! recover {
             remove_dir_if_exist(&config.data_directory).unwrap();
         }
@@ -253,13 +1028,24 @@ impl InnerQueue {
             backend: QueueBackend::new(config, recover),
             channels: Default::default(),
             state: QueueState::Ready,
+            format_version: FORMAT_VERSION,
+            jitter_rng: Mutex::new(JitterRng::new(rand::random())),
+            bytes_produced: AtomicUsize::new(0),
+            bytes_delivered: AtomicUsize::new(0),
+            watermarks: Mutex::new(Watermarks{high: 0, low: 0, paused: false, callback: None}),
+            drained: Arc::new((Mutex::new(0), Condvar::new())),
         };
         if recover {
            queue.recover();
+           if queue.state == QueueState::Deleting {
+               warn!("[{}] found in the middle of deletion, deleting", queue.config.name);
+               queue.delete();
+               return Err(QueueError::WasDeleting)
+           }
         } else {
            queue.checkpoint(false);
         }
-        queue
+        Ok(queue)
     }
 
     fn set_state(&mut self, new_state: QueueState) {
@@ -268,19 +1054,45 @@ impl InnerQueue {
         }
         match self.state {
             QueueState::Deleting => panic!("Deleting can't be reverted"),
-            QueueState::Ready => (),
+            QueueState::Ready | QueueState::Draining => (),
         }
+        info!("[{}] state changed from {:?} to {:?}", self.config.name, self.state, new_state);
         self.state = new_state;
     }
 
     pub fn create_channel(&mut self, channel_name: &str, clock: u32) -> QueueResult<()> {
+        let head = self.backend.head();
+        self.create_channel_at(channel_name, head, false, clock)
+    }
+
+    pub fn replay_to_channel(&mut self, channel_name: &str, from_id: u64, clock: u32) -> QueueResult<()> {
+        if from_id < self.backend.tail() || from_id > self.backend.head() {
+            return Err(QueueError::IdOutOfRange)
+        }
+        self.create_channel_at(channel_name, from_id, false, clock)
+    }
+
+    fn create_channel_at(&mut self, channel_name: &str, tail: u64, auto_ack: bool, clock: u32)
+            -> QueueResult<()> {
         if let Entry::Vacant(vacant_entry) = self.channels.entry(channel_name.into()) {
             let channel = Channel {
                 last_touched: clock,
                 expired_count: 0,
-                tail: self.backend.head(),
+                tail: tail,
                 in_flight_map: Default::default(),
                 in_flight_heap: Default::default(),
+                expiration_heap: Default::default(),
+                timed_out: Default::default(),
+                dead_letters: Default::default(),
+                outgoing: Default::default(),
+                nacked: Default::default(),
+                delayed: Default::default(),
+                priority: Default::default(),
+                sent_ahead: Default::default(),
+                dlq: None,
+                read_ahead: None,
+                auto_ack: auto_ack,
+                paused: false,
             };
             debug!("[{}] creating channel {:?}", self.config.name, channel);
             vacant_entry.insert(Mutex::new(channel));
@@ -290,6 +1102,48 @@ impl InnerQueue {
         }
     }
 
+    pub fn seek_channel(&self, channel_name: &str, id: u64, clock: u32) -> QueueResult<()> {
+        if id < self.backend.tail() || id > self.backend.head() {
+            return Err(QueueError::IdOutOfRange)
+        }
+        if let Some(channel) = self.channels.get(channel_name) {
+            info!("[{}:{}] seeking to {}", self.config.name, channel_name, id);
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.purge(id);
+            locked_channel.last_touched = clock;
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    fn idle_channels(&self, clock: u32) -> Vec<String> {
+        let channel_ttl = self.config.channel_ttl;
+        if channel_ttl == 0 {
+            return Vec::new()
+        }
+        self.channels.iter()
+            .filter(|&(_, c)| {
+                let locked_channel = c.lock().unwrap();
+                !locked_channel.paused && clock.saturating_sub(locked_channel.last_touched) > channel_ttl
+            })
+            .map(|(channel_name, _)| String::from(&channel_name[..]))
+            .collect()
+    }
+
+    pub fn expire_idle_channels(&mut self, clock: u32) -> Vec<String> {
+        let idle_channels = self.idle_channels(clock);
+        for channel_name in &idle_channels {
+            info!("[{}:{}] idle for more than {}s, deleting",
+                self.config.name, channel_name, self.config.channel_ttl);
+            self.channels.remove(&channel_name[..]);
+        }
+        if !idle_channels.is_empty() {
+            self.checkpoint(false);
+        }
+        idle_channels
+    }
+
     pub fn delete_channel(&mut self, channel_name: &str) -> QueueResult<()> {
         if self.channels.remove(channel_name).is_some() {
             Ok(())
@@ -309,6 +1163,27 @@ impl InnerQueue {
         }
     }
 
+    pub fn merge_channel(&mut self, into: &str, from: &str, clock: u32) -> QueueResult<()> {
+        if !self.channels.contains_key(into) || !self.channels.contains_key(from) {
+            return Err(QueueError::ChannelNotFound)
+        }
+        if into == from {
+            return Ok(())
+        }
+        let from_tail = self.channels.remove(from).unwrap().into_inner().unwrap().real_tail();
+        {
+            let mut locked_channel = self.channels[into].lock().unwrap();
+            // in flight messages are dropped, so make sure they're delivered again
+            let new_tail = cmp::min(locked_channel.real_tail(), from_tail);
+            info!("[{}] merging channel {} into {}, new tail is {}",
+                self.config.name, from, into, new_tail);
+            locked_channel.purge(new_tail);
+            locked_channel.last_touched = clock;
+        }
+        self.checkpoint(false);
+        Ok(())
+    }
+
     pub fn iter_channels<F: Fn(&Atom, &Channel)>(&self, clock: u32, cb: F) {
         for (channel_name, channel) in &self.channels {
             let mut locked_channel = channel.lock().unwrap();
@@ -317,105 +1192,688 @@ impl InnerQueue {
         }
     }
 
-    /// get access is suposed to be thread-safe, even while writing
-    pub fn get(&self, channel_name: &str, clock: u32) -> QueueResult<(i64, Message)> {
-        if let Some(channel) = self.channels.get(channel_name) {
+    /// the timeout of a message sent again after retry timeouts,
+    /// it doubles every time up to max_backoff, if set
+    fn backoff(&self, timeout: u32, retry: u32) -> u32 {
+        if self.config.max_backoff == 0 {
+            return timeout
+        }
+        let backoff = cmp::min((timeout as u64) << cmp::min(retry, 32), self.config.max_backoff as u64);
+        cmp::max(timeout, backoff as u32)
+    }
+
+    /// the expiration for a message fetched now
+    fn expiration(&self, timeout: u32, clock: u32) -> u32 {
+        let jitter = if self.config.redelivery_jitter != 0 {
+            self.jitter_rng.lock().unwrap().next(self.config.redelivery_jitter)
+        } else {
+            0
+        };
+        clock + timeout + jitter
+    }
+
+    /// get access is suposed to be thread-safe, even while writing
+    pub fn get(&self, channel_name: &str, timeout: u32, clock: u32) -> QueueResult<(i64, Message)> {
+        let _span = trace_span!("get", queue = %self.config.name, channel = channel_name);
+        let mut dead_letters = Vec::new();
+        let result = self.get_locked(channel_name, timeout, clock, &mut dead_letters);
+        // dead letters are routed after the channel lock is released,
+        // as the target may be another channel
+        for (target, id) in dead_letters {
+            self.route_dead_letter(channel_name, target, id);
+        }
+        result
+    }
+
+    fn get_locked(&self, channel_name: &str, timeout: u32, clock: u32,
+            dead_letters: &mut Vec<(DlqTarget, u64)>) -> QueueResult<(i64, Message)> {
+        if let Some(channel) = self.channels.get(channel_name) {
             let mut locked_channel = channel.lock().unwrap();
+            self.get_from(channel_name, &mut *locked_channel, timeout, clock, dead_letters)
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
 
-            locked_channel.last_touched = clock;
+    fn get_from(&self, channel_name: &str, locked_channel: &mut Channel, timeout: u32, clock: u32,
+            dead_letters: &mut Vec<(DlqTarget, u64)>) -> QueueResult<(i64, Message)> {
+        let (ticket, mut message) = try!(
+            self.next_from(channel_name, locked_channel, timeout, clock, dead_letters));
+        if !message.is_intact() {
+            let id = message.id();
+            drop(message);
+            // it would be the same every time it's sent
+            let _ = self.ack_from(channel_name, locked_channel, ticket, clock);
+            self.backend.recent_errors().error(format!(
+                "[{}:{}] corrupt message with id {}", self.config.name, channel_name, id));
+            return Err(QueueError::CorruptMessage(id))
+        }
+        if !message.decrypt() {
+            let id = message.id();
+            drop(message);
+            // not acked, a wrong key shouldn't lose messages
+            self.backend.recent_errors().error(format!(
+                "[{}:{}] can't decrypt message with id {}", self.config.name, channel_name, id));
+            return Err(QueueError::DecryptionFailed(id))
+        }
+        Ok((ticket, message))
+    }
 
-            if let Some((&ticket, &InFlightState{expiration, ..})) = locked_channel.in_flight_map.front() {
-                // check in flight queue for timeouts
-                if clock >= expiration {
-                    if expiration == EXPIRED {
-                        locked_channel.expired_count -= 1;
+    fn next_from(&self, channel_name: &str, locked_channel: &mut Channel, timeout: u32, clock: u32,
+            dead_letters: &mut Vec<(DlqTarget, u64)>) -> QueueResult<(i64, Message)> {
+        locked_channel.last_touched = clock;
+
+        if locked_channel.paused {
+            trace_event!(outcome = "paused");
+            return Err(QueueError::EndOfQueue(locked_channel.tail))
+        }
+
+        // check in flight queue for timeouts, then nacked messages
+        loop {
+            let mut state = if let Some(state) = locked_channel.pop_timed_out(clock) {
+                state
+            } else if let Some(state) = locked_channel.nacked.pop_front() {
+                state
+            } else {
+                break
+            };
+            let id = state.id;
+            state.retry += 1;
+            if self.config.max_retries != 0 && state.retry > self.config.max_retries {
+                let target = locked_channel.dlq.clone().or_else(|| {
+                    if self.config.dead_letter_queue.is_empty() {
+                        None
+                    } else {
+                        Some(DlqTarget::Queue(self.config.dead_letter_queue.clone()))
+                    }
+                });
+                match target {
+                    // see Queue::forward_dead_letters
+                    Some(DlqTarget::Queue(queue_name)) => {
+                        debug!("[{}:{}] msg {} exceeded {} retries, dead lettering to queue {}",
+                            self.config.name, channel_name, id, self.config.max_retries, queue_name);
+                        locked_channel.outgoing.push_back((DlqTarget::Queue(queue_name), id));
+                    }
+                    Some(target) => {
+                        debug!("[{}:{}] msg {} exceeded {} retries, dead lettering to {:?}",
+                            self.config.name, channel_name, id, self.config.max_retries, target);
+                        locked_channel.outgoing.push_back((target.clone(), id));
+                        dead_letters.push((target, id));
                     }
-                    let mut state = locked_channel.in_flight_map.remove(&ticket).unwrap();
-                    let id = state.id;
-                    // make sure ticket is in i64 range
-                    let ticket = rand::random::<i64>();
-                    state.expiration = clock + self.config.message_timeout;
-                    locked_channel.in_flight_map.insert(ticket, state);
-                    locked_channel.in_flight_heap.push((Rev(id), ticket));
-                    debug!("[{}:{}] msg {} expired and will be sent again as ticket {}",
+                    None => {
+                        warn!("[{}:{}] msg {} exceeded {} retries and no dead letter target is set, discarding",
+                            self.config.name, channel_name, id, self.config.max_retries);
+                    }
+                }
+                locked_channel.pop_stale_heap();
+                trace_event!(id = id, outcome = "dead_lettered");
+                continue
+            }
+            let message = match self.backend.get(id) {
+                Some(message) => message,
+                None => {
+                    warn!("[{}:{}] timed out msg {} is gone", self.config.name, channel_name, id);
+                    locked_channel.pop_stale_heap();
+                    continue
+                }
+            };
+            if message.id() != id || message.is_expired(clock) {
+                locked_channel.pop_stale_heap();
+                trace_event!(id = id, outcome = "expired");
+                continue
+            }
+            // make sure ticket is in i64 range
+            let ticket = rand::random::<i64>();
+            state.expiration = self.expiration(self.backoff(timeout, state.retry), clock);
+            locked_channel.insert_in_flight(ticket, state);
+            debug!("[{}:{}] msg {} expired and will be sent again as ticket {}",
+                self.config.name, channel_name, id, ticket);
+            trace_event!(id = id, outcome = "redelivery");
+            self.bytes_delivered.fetch_add(message.body_len(), Ordering::Relaxed);
+            return Ok((ticket, message))
+        }
+
+        // the next ones would be new in flight messages
+        if self.config.max_in_flight != 0 &&
+                locked_channel.in_flight_map.len() >= self.config.max_in_flight as usize {
+            debug!("[{}:{}] max in flight messages reached", self.config.name, channel_name);
+            trace_event!(outcome = "max_in_flight");
+            return Err(QueueError::EndOfQueue(locked_channel.tail))
+        }
+
+        // then messages dead lettered into this channel
+        while let Some(id) = locked_channel.dead_letters.pop_front() {
+            match self.backend.get(id) {
+                Some(ref message) if message.id() != id || message.is_expired(clock) => (),
+                Some(message) => {
+                    let ticket = self.add_in_flight(&mut *locked_channel, id, timeout, clock);
+                    debug!("[{}:{}] fetched dead lettered msg {} as ticket {}",
                         self.config.name, channel_name, id, ticket);
-                    return Ok((ticket, self.backend.get(id).unwrap()))
+                    trace_event!(id = id, outcome = "dead_letter");
+                    self.bytes_delivered.fetch_add(message.body_len(), Ordering::Relaxed);
+                    return Ok((ticket, message))
                 }
+                None => (),
             }
+            warn!("[{}:{}] dead lettered msg {} is gone", self.config.name, channel_name, id);
+        }
 
-            // fetch from the backend
-            return if let Some(message) = self.backend.get(locked_channel.tail) {
-                let ticket = rand::random::<i64>();
-                let id = message.id();
-                let state = InFlightState {
-                    id: id,
-                    expiration: clock + self.config.message_timeout,
-                };
-                locked_channel.in_flight_map.insert(ticket, state);
-                locked_channel.in_flight_heap.push((Rev(id), ticket));
+        // then delayed messages that are due
+        while let Some(&(Rev(deliver_at), Rev(id))) = locked_channel.delayed.peek() {
+            if clock < deliver_at {
+                break
+            }
+            locked_channel.delayed.pop();
+            match self.backend.get(id) {
+                Some(ref message) if message.id() != id || message.is_expired(clock) => (),
+                Some(message) => {
+                    let ticket = self.add_in_flight(&mut *locked_channel, id, timeout, clock);
+                    debug!("[{}:{}] fetched delayed msg {} as ticket {}",
+                        self.config.name, channel_name, id, ticket);
+                    trace_event!(id = id, outcome = "delayed");
+                    self.bytes_delivered.fetch_add(message.body_len(), Ordering::Relaxed);
+                    return Ok((ticket, message))
+                }
+                None => (),
+            }
+        }
+
+        // then messages with a priority, ahead of the tail
+        while let Some((_, Rev(id))) = locked_channel.priority.pop() {
+            if id < locked_channel.tail {
+                // already sent in order
+                continue
+            }
+            match self.backend.get(id) {
+                Some(ref message) if message.id() != id || message.is_expired(clock) => (),
+                Some(message) => {
+                    locked_channel.sent_ahead.insert(id);
+                    let ticket = self.add_in_flight(&mut *locked_channel, id, timeout, clock);
+                    debug!("[{}:{}] fetched priority msg {} as ticket {}",
+                        self.config.name, channel_name, id, ticket);
+                    trace_event!(id = id, outcome = "priority");
+                    self.bytes_delivered.fetch_add(message.body_len(), Ordering::Relaxed);
+                    return Ok((ticket, message))
+                }
+                None => (),
+            }
+        }
+
+        // fetch from the backend
+        while let Some(message) = self.fetch(&mut *locked_channel) {
+            let id = message.id();
+            if locked_channel.sent_ahead.remove(&id) {
                 locked_channel.tail = id + 1;
-                debug!("[{}:{}] fetched msg {} from backend as ticket {}",
-                    self.config.name, channel_name, message.id(), ticket);
-                trace!("[{}:{}] advancing tail to {}",
-                    self.config.name, channel_name, locked_channel.tail);
-                Ok((ticket, message))
-            } else {
-                debug!("[{}:{}] no more messages", self.config.name, channel_name);
-                Err(QueueError::EndOfQueue(locked_channel.tail))
+                continue
+            }
+            if message.is_expired(clock) {
+                locked_channel.tail = id + 1;
+                trace_event!(id = id, outcome = "expired");
+                continue
+            }
+            if let Some(deliver_at) = message.deliver_at() {
+                if clock < deliver_at {
+                    // keep it aside and go on with the next ones
+                    locked_channel.delayed.push((Rev(deliver_at), Rev(id)));
+                    locked_channel.tail = id + 1;
+                    trace_event!(id = id, outcome = "delayed_for_later");
+                    continue
+                }
+            }
+            let ticket = self.add_in_flight(&mut *locked_channel, id, timeout, clock);
+            locked_channel.tail = id + 1;
+            debug!("[{}:{}] fetched msg {} from backend as ticket {}",
+                self.config.name, channel_name, message.id(), ticket);
+            trace!("[{}:{}] advancing tail to {}",
+                self.config.name, channel_name, locked_channel.tail);
+            trace_event!(id = id, outcome = "fresh");
+            self.bytes_delivered.fetch_add(message.body_len(), Ordering::Relaxed);
+            return Ok((ticket, message))
+        }
+        debug!("[{}:{}] no more messages", self.config.name, channel_name);
+        trace_event!(outcome = "empty");
+        Err(QueueError::EndOfQueue(locked_channel.tail))
+    }
+
+    /// fetch the message at the channel tail, possibly from the read ahead
+    fn fetch(&self, channel: &mut Channel) -> Option<Message> {
+        // the messages below the queue tail are gone, go on from the first available
+        let tail = cmp::max(channel.tail, self.backend.tail());
+        if self.config.read_ahead <= 1 {
+            return self.backend.get(tail)
+        }
+        if let Some(message) = channel.read_ahead.as_mut().and_then(|r| r.next(tail)) {
+            return Some(message)
+        }
+        if let Some((message, read_ahead)) = self.backend.read_ahead(tail, self.config.read_ahead as usize) {
+            channel.read_ahead = Some(read_ahead);
+            Some(message)
+        } else {
+            channel.read_ahead = None;
+            None
+        }
+    }
+
+    fn add_in_flight(&self, channel: &mut Channel, id: u64, timeout: u32, clock: u32) -> i64 {
+        // make sure ticket is in i64 range
+        let ticket = rand::random::<i64>();
+        if channel.auto_ack {
+            return ticket
+        }
+        let state = InFlightState {
+            id: id,
+            expiration: self.expiration(timeout, clock),
+            retry: 0,
+        };
+        channel.insert_in_flight(ticket, state);
+        ticket
+    }
+
+    fn route_dead_letter(&self, channel_name: &str, target: DlqTarget, id: u64) {
+        match target {
+            DlqTarget::Channel(ref target_name) => {
+                if let Some(target_channel) = self.channels.get(&target_name[..]) {
+                    let mut locked_target = target_channel.lock().unwrap();
+                    // otherwise the target will get to it anyway
+                    if id < locked_target.tail {
+                        locked_target.dead_letters.push_back(id);
+                    }
+                } else {
+                    warn!("[{}:{}] dead letter channel {} not found, dropping msg {}",
+                        self.config.name, channel_name, target_name, id);
+                }
+            }
+            DlqTarget::Queue(_) => unreachable!(),
+        }
+        // the source keeps it until now, so no checkpoint in between misses it
+        if let Some(channel) = self.channels.get(channel_name) {
+            let mut locked_channel = channel.lock().unwrap();
+            let position = locked_channel.outgoing.iter()
+                .position(|&(ref t, i)| i == id && *t == target);
+            if let Some(position) = position {
+                locked_channel.outgoing.remove(position);
+            }
+        }
+    }
+
+    /// (channel name, queue name, id, body) of the messages dead lettered to other queues,
+    /// the body is None if the message can't be read anymore
+    fn outgoing_dead_letters(&self) -> Vec<(Atom, String, u64, Option<Vec<u8>>)> {
+        let mut outgoing = Vec::new();
+        for (channel_name, channel) in &self.channels {
+            let locked_channel = channel.lock().unwrap();
+            for &(ref target, id) in &locked_channel.outgoing {
+                let queue_name = match *target {
+                    DlqTarget::Queue(ref queue_name) => queue_name.clone(),
+                    // routed right after being dead lettered
+                    DlqTarget::Channel(_) => continue,
+                };
+                let body = match self.backend.get(id) {
+                    Some(ref mut message) if message.id() == id => {
+                        if message.decrypt() {
+                            Some(message.body().unwrap().to_vec())
+                        } else {
+                            warn!("[{}:{}] can't decrypt msg {}, discarding",
+                                self.config.name, &channel_name[..], id);
+                            None
+                        }
+                    }
+                    _ => {
+                        warn!("[{}:{}] dead lettered msg {} is gone",
+                            self.config.name, &channel_name[..], id);
+                        None
+                    }
+                };
+                outgoing.push((channel_name.clone(), queue_name, id, body));
+            }
+        }
+        outgoing
+    }
+
+    fn remove_outgoing_dead_letters(&self, outgoing: Vec<(Atom, String, u64, Option<Vec<u8>>)>) {
+        for (channel_name, queue_name, id, _) in outgoing {
+            if let Some(channel) = self.channels.get(&channel_name) {
+                let mut locked_channel = channel.lock().unwrap();
+                let target = DlqTarget::Queue(queue_name);
+                let position = locked_channel.outgoing.iter()
+                    .position(|&(ref t, i)| i == id && *t == target);
+                if let Some(position) = position {
+                    locked_channel.outgoing.remove(position);
+                }
+            }
+        }
+    }
+
+    pub fn nack(&self, channel_name: &str, ticket: i64, clock: u32) -> QueueResult<()> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.last_touched = clock;
+            match locked_channel.in_flight_map.get(&ticket) {
+                Some(state) if clock < state.expiration => (),
+                _ => return Err(QueueError::TicketNotFound)
+            };
+            let state = locked_channel.in_flight_map.remove(&ticket).unwrap();
+            debug!("[{}:{}] message {} ticket {} nacked",
+                self.config.name, channel_name, state.id, ticket);
+            trace_event!(id = state.id, outcome = "nacked");
+            locked_channel.nacked.push_back(state);
+            locked_channel.pop_stale_heap();
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    pub fn touch(&self, channel_name: &str, ticket: i64, timeout: u32, clock: u32) -> QueueResult<()> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.last_touched = clock;
+            let expiration = clock + timeout;
+            match locked_channel.in_flight_map.get(&ticket) {
+                Some(state) if clock < state.expiration => (),
+                _ => return Err(QueueError::TicketNotFound)
+            };
+            let id = {
+                let state = locked_channel.in_flight_map.get_mut(&ticket).unwrap();
+                state.expiration = expiration;
+                state.id
+            };
+            locked_channel.expiration_heap.push((Rev(expiration), Rev(id), ticket));
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    /// Number of times the message of the ticket timed out and was sent again
+    pub fn retry_count(&self, channel_name: &str, ticket: i64) -> QueueResult<u32> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            match channel.lock().unwrap().in_flight_map.get(&ticket) {
+                Some(state) => Ok(state.retry),
+                None => Err(QueueError::TicketNotFound),
+            }
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    /// Paused channels don't send messages, see `Queue::pause_channel`
+    pub fn set_channel_paused(&self, channel_name: &str, paused: bool) -> QueueResult<()> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            info!("[{}:{}] {}", self.config.name, channel_name, if paused { "pausing" } else { "resuming" });
+            channel.lock().unwrap().paused = paused;
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    /// Set (or unset) where messages exceeding max_retries are sent to
+    pub fn set_channel_dlq(&self, channel_name: &str, target: Option<DlqTarget>) -> QueueResult<()> {
+        if let Some(DlqTarget::Channel(ref target_name)) = target {
+            if !self.channels.contains_key(&target_name[..]) {
+                return Err(QueueError::ChannelNotFound)
             }
         }
-        Err(QueueError::ChannelNotFound)
+        if let Some(channel) = self.channels.get(channel_name) {
+            channel.lock().unwrap().dlq = target;
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
     }
 
     /// all calls are serialized internally
-    pub fn push(&mut self, message: &[u8], clock: u32) -> QueueResult<u64> {
+    pub fn push(&mut self, message: &[u8], expires_at: Option<u32>, deliver_at: Option<u32>, clock: u32)
+            -> QueueResult<u64> {
+        let _span = trace_span!("push", queue = %self.config.name);
         trace!("[{}] putting message w/ clock {}", self.config.name, clock);
-        Ok(try!(self.backend.push(message, clock)))
+        if self.state == QueueState::Draining {
+            return Err(QueueError::QueueDraining)
+        }
+        try!(self.check_message_size(message));
+        let required_version = if self.config.encryption.is_some() {
+            5
+        } else if deliver_at.is_some() {
+            4
+        } else if expires_at.is_some() {
+            3
+        } else {
+            1
+        };
+        if self.format_version < required_version {
+            return Err(QueueError::FormatUpgradeRequired(required_version))
+        }
+        let id = try!(self.backend.push_with(message, clock, expires_at, deliver_at));
+        trace_event!(id = id, outcome = "pushed");
+        self.bytes_produced.fetch_add(message.len(), Ordering::Relaxed);
+        self.check_watermarks();
+        Ok(id)
+    }
+
+    fn check_message_size(&self, message: &[u8]) -> QueueResult<()> {
+        let max_message_size = self.config.max_message_size;
+        if max_message_size != 0 && message.len() as u64 > max_message_size {
+            warn!("[{}] rejecting message of {} bytes, max_message_size is {}",
+                self.config.name, message.len(), max_message_size);
+            return Err(QueueBackendError::MessageTooBig.into())
+        }
+        Ok(())
+    }
+
+    pub fn push_priority(&mut self, message: &[u8], priority: u8, clock: u32) -> QueueResult<u64> {
+        let id = try!(self.push(message, None, None, clock));
+        if priority != 0 {
+            for channel in self.channels.values() {
+                channel.lock().unwrap().priority.push((priority, Rev(id)));
+            }
+        }
+        Ok(id)
     }
 
     /// all calls are serialized internally
     pub fn push_many(&mut self, messages: &[&[u8]], clock: u32) -> QueueResult<u64> {
+        let _span = trace_span!("push", queue = %self.config.name, count = messages.len());
         trace!("[{}] putting {} messages w/ clock {}", self.config.name, messages.len(), clock);
         assert!(messages.len() > 0);
+        if self.state == QueueState::Draining {
+            return Err(QueueError::QueueDraining)
+        }
+        // nothing is pushed if any of them is rejected
+        for message in messages {
+            try!(self.check_message_size(message));
+        }
         for message in &messages[..messages.len() - 1] {
-            try!(self.backend.push(message, clock));
+            let _id = try!(self.backend.push(message, clock));
+            trace_event!(id = _id, outcome = "pushed");
+            self.bytes_produced.fetch_add(message.len(), Ordering::Relaxed);
         }
-        Ok(try!(self.backend.push(messages[messages.len() - 1], clock)))
+        let message = messages[messages.len() - 1];
+        let id = try!(self.backend.push(message, clock));
+        trace_event!(id = id, outcome = "pushed");
+        self.bytes_produced.fetch_add(message.len(), Ordering::Relaxed);
+        self.check_watermarks();
+        Ok(id)
+    }
+
+    /// all calls are serialized internally
+    pub fn push_each(&mut self, messages: &[&[u8]], clock: u32) -> Vec<QueueResult<u64>> {
+        let _span = trace_span!("push", queue = %self.config.name, count = messages.len());
+        trace!("[{}] putting {} messages w/ clock {}", self.config.name, messages.len(), clock);
+        let results = messages.iter().map(|message| {
+            if self.state == QueueState::Draining {
+                return Err(QueueError::QueueDraining)
+            }
+            try!(self.check_message_size(message));
+            let id = try!(self.backend.push(message, clock));
+            trace_event!(id = id, outcome = "pushed");
+            self.bytes_produced.fetch_add(message.len(), Ordering::Relaxed);
+            Ok(id)
+        }).collect();
+        self.check_watermarks();
+        results
     }
 
     /// ack access is suposed to be thread-safe, even while writing
     pub fn ack(&self, channel_name: &str, ticket: i64, clock: u32) -> QueueResult<()> {
+        let _span = trace_span!("ack", queue = %self.config.name, channel = channel_name);
         if let Some(channel) = self.channels.get(channel_name) {
             let mut locked_channel = channel.lock().unwrap();
             locked_channel.last_touched = clock;
+            try!(self.ack_from(channel_name, &mut *locked_channel, ticket, clock));
+            let drained = locked_channel.is_drained(self.backend.head());
+            drop(locked_channel);
+            if drained {
+                self.notify_drained();
+            }
+            Ok(())
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
 
-            // try to remove the ticket if not expired
-            // TODO: success rate should be much higher, so remove and re-add if needed
-            match locked_channel.in_flight_map.get(&ticket) {
-                Some(state) if clock < state.expiration => (),
-                _ => return Err(QueueError::TicketNotFound),
-            };
+    fn ack_from(&self, channel_name: &str, locked_channel: &mut Channel, ticket: i64, clock: u32)
+            -> QueueResult<()> {
+        // try to remove the ticket if not expired
+        // TODO: success rate should be much higher, so remove and re-add if needed
+        match locked_channel.in_flight_map.get(&ticket) {
+            Some(state) if clock < state.expiration => (),
+            _ => {
+                trace_event!(ticket = ticket, outcome = "ticket_not_found");
+                return Err(QueueError::TicketNotFound)
+            }
+        };
 
-            let state = locked_channel.in_flight_map.remove(&ticket).unwrap();
-            trace!("[{}:{}] message {} ticket {} deleted from channel",
-                self.config.name, channel_name, state.id, ticket);
-            // advance channel real tail
-            while locked_channel.in_flight_heap
-                    .peek()
-                    .map_or(false, |&(_, ticket)| !locked_channel.in_flight_map.contains_key(&ticket)) {
-                locked_channel.in_flight_heap.pop();
+        let state = locked_channel.in_flight_map.remove(&ticket).unwrap();
+        trace!("[{}:{}] message {} ticket {} deleted from channel",
+            self.config.name, channel_name, state.id, ticket);
+        trace_event!(id = state.id, outcome = "acked");
+        // advance channel real tail
+        locked_channel.pop_stale_heap();
+        Ok(())
+    }
+
+    pub fn get_batch_ack(&self, channel_name: &str, ack_tickets: &[i64], count: usize, clock: u32)
+            -> QueueResult<Vec<(i64, Message)>> {
+        match self.get_batch(channel_name, ack_tickets, count, clock) {
+            Err(QueueError::EndOfQueue(_)) => Ok(Vec::new()),
+            result => result
+        }
+    }
+
+    /// Acks the tickets, ignoring the ones not found, then gets up to count messages
+    /// all while holding the channel lock. EndOfQueue is only returned if none could be fetched
+    pub fn get_batch(&self, channel_name: &str, ack_tickets: &[i64], count: usize, clock: u32)
+            -> QueueResult<Vec<(i64, Message)>> {
+        let _span = trace_span!("get_batch", queue = %self.config.name, channel = channel_name);
+        let mut dead_letters = Vec::new();
+        let mut messages = Vec::with_capacity(count);
+        let mut end_of_queue = None;
+        let mut failure = None;
+        let drained = if let Some(channel) = self.channels.get(channel_name) {
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.last_touched = clock;
+            for &ticket in ack_tickets {
+                let _ = self.ack_from(channel_name, &mut *locked_channel, ticket, clock);
             }
-            Ok(())
+            for _ in 0..count {
+                match self.get_from(channel_name, &mut *locked_channel, self.config.message_timeout,
+                        clock, &mut dead_letters) {
+                    Ok(result) => messages.push(result),
+                    Err(QueueError::EndOfQueue(tail)) => {
+                        end_of_queue = Some(tail);
+                        break
+                    }
+                    // already recorded, don't lose the ones fetched so far
+                    Err(QueueError::CorruptMessage(_)) | Err(QueueError::DecryptionFailed(_))
+                        if !messages.is_empty() => break,
+                    // still route the dead letters collected so far
+                    Err(error) => {
+                        failure = Some(error);
+                        break
+                    }
+                }
+            }
+            locked_channel.is_drained(self.backend.head())
+        } else {
+            return Err(QueueError::ChannelNotFound)
+        };
+        for (target, id) in dead_letters {
+            self.route_dead_letter(channel_name, target, id);
+        }
+        if drained {
+            self.notify_drained();
+        }
+        if let Some(error) = failure {
+            return Err(error)
+        }
+        match end_of_queue {
+            Some(tail) if messages.is_empty() => Err(QueueError::EndOfQueue(tail)),
+            _ => Ok(messages)
+        }
+    }
+
+    pub fn get_by_id(&self, id: u64) -> QueueResult<Message> {
+        match self.backend.get(id) {
+            // the backend returns the next message for ids in missing segments
+            Some(ref message) if message.id() != id => Err(QueueError::IdOutOfRange),
+            Some(mut message) => if !message.is_intact() {
+                self.backend.recent_errors().error(format!(
+                    "[{}] corrupt message with id {}", self.config.name, id));
+                Err(QueueError::CorruptMessage(id))
+            } else if !message.decrypt() {
+                self.backend.recent_errors().error(format!(
+                    "[{}] can't decrypt message with id {}", self.config.name, id));
+                Err(QueueError::DecryptionFailed(id))
+            } else {
+                Ok(message)
+            },
+            None => Err(QueueError::IdOutOfRange),
+        }
+    }
+
+    pub fn peek(&self, channel_name: &str, clock: u32) -> QueueResult<Message> {
+        let mut id = if let Some(channel) = self.channels.get(channel_name) {
+            cmp::max(channel.lock().unwrap().tail, self.backend.tail())
+        } else {
+            return Err(QueueError::ChannelNotFound)
+        };
+        while let Some(mut message) = self.backend.get(id) {
+            if !message.is_expired(clock) && !message.is_delayed(clock) {
+                if !message.decrypt() {
+                    return Err(QueueError::DecryptionFailed(message.id()))
+                }
+                return Ok(message)
+            }
+            id = message.id() + 1;
+        }
+        Err(QueueError::EndOfQueue(id))
+    }
+
+    pub fn channel_is_drained(&self, channel_name: &str) -> QueueResult<bool> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            Ok(channel.lock().unwrap().is_drained(self.backend.head()))
         } else {
             Err(QueueError::ChannelNotFound)
         }
     }
 
+    fn is_drained(&self) -> bool {
+        let head = self.backend.head();
+        self.channels.values().all(|c| c.lock().unwrap().is_drained(head))
+    }
+
+    /// wake up wait_empty callers, channel locks must not be held
+    fn notify_drained(&self) {
+        *self.drained.0.lock().unwrap() += 1;
+        self.drained.1.notify_all();
+    }
+
     /// Set the channel tail to the specified id
     pub fn seek_channel_to_id(&self, channel_name: &str, id: u64, clock: u32) -> QueueResult<()> {
         if let Some(channel) = self.channels.get(channel_name) {
             let mut locked_channel = channel.lock().unwrap();
-            locked_channel.tail = id;
+            locked_channel.seek(id);
             locked_channel.last_touched = clock;
             Ok(())
         } else {
@@ -427,7 +1885,7 @@ impl InnerQueue {
     pub fn seek_channel_to_timestamp(&self, channel_name: &str, timestamp: u32, clock: u32) -> QueueResult<()> {
         if let Some(channel) = self.channels.get(channel_name) {
             let mut locked_channel = channel.lock().unwrap();
-            locked_channel.tail = self.backend.find_id_for_timestamp(timestamp);
+            locked_channel.seek(self.backend.find_id_for_timestamp(timestamp));
             locked_channel.last_touched = clock;
             Ok(())
         } else {
@@ -435,16 +1893,26 @@ impl InnerQueue {
         }
     }
 
-    pub fn purge(&mut self) {
-        info!("[{}] purging", self.config.name);
+    pub fn purge(&mut self) -> u64 {
+        let purged = self.backend.head() - self.backend.tail();
+        info!("[{}] purging {} messages", self.config.name, purged);
         self.backend.purge();
+        let new_tail = self.backend.tail();
         for (_, channel) in &mut self.channels {
-            let mut locked_channel = channel.lock().unwrap();
-            locked_channel.tail = self.backend.tail();
-            locked_channel.in_flight_map.clear();
-            locked_channel.expired_count = 0;
+            channel.lock().unwrap().purge(new_tail);
+        }
+        self.checkpoint(false);
+        purged
+    }
+
+    pub fn truncate_to(&mut self, id: u64) {
+        info!("[{}] truncating to {}", self.config.name, id);
+        self.backend.truncate_to(id);
+        let new_tail = self.backend.tail();
+        for (_, channel) in &mut self.channels {
+            channel.lock().unwrap().truncate(new_tail);
         }
-        self.as_mut().checkpoint(false);
+        self.checkpoint(false);
     }
 
     pub fn info(&self, clock: u32) -> QueueInfo {
@@ -454,6 +1922,8 @@ impl InnerQueue {
             channels: Default::default(),
             segments_count: self.backend.segments_count() as u32,
             disk_usage: self.backend.disk_usage(),
+            bytes_produced: self.bytes_produced.load(Ordering::Relaxed) as u64,
+            bytes_delivered: self.bytes_delivered.load(Ordering::Relaxed) as u64,
         };
         for (channel_name, channel) in &self.channels {
             let mut locked_channel = channel.lock().unwrap();
@@ -467,12 +1937,100 @@ impl InnerQueue {
         q_info
     }
 
-    pub fn delete(&mut self) {
-        info!("[{}] deleting", self.config.name);
-        self.as_mut().set_state(QueueState::Deleting);
-        self.as_mut().checkpoint(false);
+    pub fn health(&self, lag_threshold: u64, clock: u32) -> Health {
+        let head = self.backend.head();
+        let channels_behind = self.channels.values()
+            .filter(|c| head.saturating_sub(c.lock().unwrap().real_tail()) > lag_threshold)
+            .count();
+        Health {
+            state: self.state,
+            writable: self.state == QueueState::Ready,
+            oldest_age: self.backend.get(self.backend.tail())
+                .map(|m| clock.saturating_sub(m.timestamp())),
+            channels_behind: channels_behind,
+        }
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            head: self.backend.head(),
+            tail: self.backend.tail(),
+            files_count: self.backend.segments_count(),
+            channel_count: self.channels.len(),
+            total_in_flight: self.channels.values()
+                .map(|c| c.lock().unwrap().in_flight_map.len())
+                .sum(),
+            state: self.state,
+        }
+    }
+
+    pub fn status(&self, clock: u32) -> QueueStatus {
+        let head = self.backend.head();
+        let mut status = QueueStatus {
+            name: self.config.name.clone(),
+            state: self.state,
+            head: head,
+            tail: self.backend.tail(),
+            channels: Default::default(),
+        };
+        for (channel_name, channel) in &self.channels {
+            let mut locked_channel = channel.lock().unwrap();
+            status.channels.insert(String::from(&channel_name[..]), ChannelStatus {
+                tail: locked_channel.tail,
+                in_flight_count: locked_channel.in_flight_count(clock),
+                lag: head.saturating_sub(locked_channel.real_tail()),
+            });
+        }
+        status
+    }
+
+    fn channel_lag_secs(&self, channel: &Channel, clock: u32) -> u32 {
+        let real_tail = channel.real_tail();
+        if real_tail >= self.backend.head() {
+            return 0
+        }
+        self.backend.get(cmp::max(real_tail, self.backend.tail()))
+            .map_or(0, |m| clock.saturating_sub(m.timestamp()))
+    }
+
+    pub fn upgrade_format(&mut self) -> io::Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("format version {} can't be downgraded to {}", self.format_version, FORMAT_VERSION)))
+        }
+        if self.format_version < FORMAT_VERSION {
+            info!("[{}] upgrading format version from {} to {}",
+                self.config.name, self.format_version, FORMAT_VERSION);
+            // messages are written the same way, only flagged ones need a newer version,
+            // version 2 only adds optional checkpoint fields, rewriting them is enough
+            // versions 3 and 4 allow expiring and delayed messages, 5 the encrypted ones
+            let old_version = self.format_version;
+            self.format_version = FORMAT_VERSION;
+            if let Err(error) = self.try_checkpoint(true) {
+                // nothing newer may be written until it's on disk
+                self.format_version = old_version;
+                return Err(error)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self) -> u64 {
+        let deleted = self.backend.head() - self.backend.tail();
+        info!("[{}] deleting with {} messages", self.config.name, deleted);
+        self.set_state(QueueState::Deleting);
+        self.checkpoint(false);
         self.backend.delete();
         remove_dir_if_exist(&self.config.data_directory).unwrap();
+        deleted
+    }
+
+    pub fn close(mut self) -> io::Result<()> {
+        info!("[{}] closing", self.config.name);
+        if self.state != QueueState::Deleting {
+            self.checkpoint(true);
+        }
+        self.backend.close()
     }
 
     fn recover(&mut self) {
@@ -485,8 +2043,8 @@ impl InnerQueue {
                 match checkpoint_result {
                     Ok(state) => state,
                     Err(error) => {
-                        error!("[{}] error parsing checkpoint information: {}",
-                            self.config.name, error);
+                        self.backend.recent_errors().error(format!("[{}] error parsing checkpoint information: {}",
+                            self.config.name, error));
                         return;
                     }
                 }
@@ -501,9 +2059,17 @@ impl InnerQueue {
         info!("[{}] checkpoint loaded: {:?}", self.config.name, queue_checkpoint.state);
 
         self.state = queue_checkpoint.state;
+        self.format_version = queue_checkpoint.version.unwrap_or(1);
+        if self.format_version > FORMAT_VERSION {
+            warn!("[{}] format version {} is newer than the supported {}",
+                self.config.name, self.format_version, FORMAT_VERSION);
+        } else if self.format_version < FORMAT_VERSION {
+            info!("[{}] format version {} can be upgraded to {}",
+                self.config.name, self.format_version, FORMAT_VERSION);
+        }
 
         match self.state {
-            QueueState::Ready => {
+            QueueState::Ready | QueueState::Draining => {
                 for (channel_name, channel_checkpoint) in queue_checkpoint.channels {
                     self.channels.insert(
                         channel_name.into(),
@@ -512,39 +2078,82 @@ impl InnerQueue {
                             expired_count: 0,
                             tail: channel_checkpoint.tail,
                             in_flight_map: Default::default(),
-                            in_flight_heap: Default::default()
+                            in_flight_heap: Default::default(),
+                            expiration_heap: Default::default(),
+                            timed_out: Default::default(),
+                            dead_letters: channel_checkpoint.dead_letters.unwrap_or_default()
+                                .into_iter().collect(),
+                            outgoing: channel_checkpoint.outgoing.unwrap_or_default()
+                                .into_iter().collect(),
+                            nacked: Default::default(),
+                            delayed: channel_checkpoint.delayed.unwrap_or_default().into_iter()
+                                .map(|(deliver_at, id)| (Rev(deliver_at), Rev(id)))
+                                .collect(),
+                            priority: Default::default(),
+                            sent_ahead: Default::default(),
+                            dlq: channel_checkpoint.dlq,
+                            read_ahead: None,
+                            auto_ack: channel_checkpoint.auto_ack.unwrap_or(false),
+                            paused: channel_checkpoint.paused.unwrap_or(false),
                         })
                     );
                 }
+                // finish routing the ones interrupted by the crash
+                let mut interrupted = Vec::new();
+                for (channel_name, channel) in &self.channels {
+                    for &(ref target, id) in &channel.lock().unwrap().outgoing {
+                        if let DlqTarget::Channel(_) = *target {
+                            interrupted.push((channel_name.clone(), target.clone(), id));
+                        }
+                    }
+                }
+                for (channel_name, target, id) in interrupted {
+                    self.route_dead_letter(&channel_name, target, id);
+                }
             }
-            QueueState::Deleting => {
-                // TODO: return some sort of error
-            }
+            // see InnerQueue::new
+            QueueState::Deleting => (),
         }
     }
 
-    fn checkpoint(&mut self, full: bool) {
-        let mut checkpoint = QueueCheckpoint {
-            state: self.state,
-            channels: Default::default()
+    fn checkpoint(&self, full: bool) {
+        if let Err(error) = self.try_checkpoint(full) {
+            self.backend.recent_errors().error(format!("[{}] error writing checkpoint information: {}",
+                self.config.name, error))
+        }
+    }
+
+    fn try_checkpoint(&self, full: bool) -> io::Result<()> {
+        let mut checkpoint = QueueCheckpoint {
+            state: self.state,
+            channels: Default::default(),
+            version: Some(self.format_version),
         };
 
-        if self.state == QueueState::Ready {
-            self.backend.checkpoint(full);
+        if self.state != QueueState::Deleting {
+            try!(self.backend.try_checkpoint(full));
             for (channel_name, channel) in &self.channels {
                 let locked_channel = channel.lock().unwrap();
                 checkpoint.channels.insert(
                     String::from(&channel_name[..]),
                     ChannelCheckpoint {
                         last_touched: locked_channel.last_touched,
-                        tail: locked_channel.real_tail(),
+                        tail: locked_channel.checkpoint_tail(),
+                        dlq: locked_channel.dlq.clone(),
+                        auto_ack: Some(locked_channel.auto_ack),
+                        paused: Some(locked_channel.paused),
+                        delayed: Some(locked_channel.delayed.iter()
+                            .map(|&(Rev(deliver_at), Rev(id))| (deliver_at, id))
+                            .collect()),
+                        dead_letters: Some(locked_channel.dead_letters.iter().cloned().collect()),
+                        outgoing: Some(locked_channel.outgoing.iter().cloned().collect()),
                     }
                 );
             }
         }
 
         let tmp_path = self.config.data_directory.join(TMP_QUEUE_CHECKPOINT_FILE);
-        let result = File::create(&tmp_path)
+        try!(File::create(&tmp_path)
             .and_then(|mut file| {
                 try!(write!(file, "{}", json::as_pretty_json(&checkpoint)));
                 file.sync_data()
@@ -552,39 +2161,136 @@ impl InnerQueue {
             .and_then(|_| {
                 let final_path = tmp_path.with_file_name(QUEUE_CHECKPOINT_FILE);
                 fs::rename(tmp_path, final_path)
-            });
+            }));
 
-        match result {
-            Ok(_) => info!("[{}] checkpointed: {:?}", self.config.name, checkpoint.state),
-            Err(error) =>
-                panic!("[{}] error writing checkpoint information: {}",
-                    self.config.name, error)
+        info!("[{}] checkpointed: {:?}", self.config.name, checkpoint.state);
+        Ok(())
+    }
+
+    fn rename(&mut self, new_name: &str, new_dir: &Path) -> io::Result<()> {
+        if new_dir.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", new_dir)))
         }
+        info!("[{}] renaming to {} at {:?}", self.config.name, new_name, new_dir);
+        try!(self.try_checkpoint(true));
+        let mut config = self.config.clone();
+        config.name = new_name.into();
+        config.data_directory = new_dir.into();
+        try!(self.backend.rename(config.clone()));
+        self.config = config.clone();
+        self.backend.set_config(config);
+        Ok(())
+    }
+
+    fn snapshot_to(&mut self, directory: &Path) -> io::Result<()> {
+        try!(fs::create_dir(directory));
+        self.checkpoint(false);
+        try!(self.backend.snapshot_to(directory));
+        try!(fs::copy(self.config.data_directory.join(QUEUE_CHECKPOINT_FILE),
+            directory.join(QUEUE_CHECKPOINT_FILE)));
+        Ok(())
+    }
+
+    fn smallest_tail(&self) -> u64 {
+        self.channels.values()
+            .map(|c| c.lock().unwrap().real_tail())
+            .min()
+            .unwrap_or(0)
     }
 
-    pub fn maintenance(&mut self, clock: u32) {
-        let smallest_tail = {
-            self.channels.values()
-                .map(|c| c.lock().unwrap().real_tail())
-                .min()
-                .unwrap_or(0)
+    /// Calls the backpressure callback if the backlog crossed the watermarks
+    fn check_watermarks(&self) {
+        let signal_callback = {
+            let mut watermarks = self.watermarks.lock().unwrap();
+            if watermarks.high == 0 || watermarks.callback.is_none() {
+                return
+            }
+            let backlog = self.backend.head().saturating_sub(self.smallest_tail());
+            let signal = if !watermarks.paused && backlog >= watermarks.high {
+                Backpressure::Pause
+            } else if watermarks.paused && backlog <= watermarks.low {
+                Backpressure::Resume
+            } else {
+                return
+            };
+            watermarks.paused = signal == Backpressure::Pause;
+            debug!("[{}] backlog is {}, signaling {:?}", self.config.name, backlog, signal);
+            (signal, watermarks.callback.clone().unwrap())
         };
+        let (signal, callback) = signal_callback;
+        callback(signal)
+    }
+
+    pub fn gc_advisable(&self) -> bool {
+        let consumed_segments = self.backend.consumed_segments(self.smallest_tail());
+        consumed_segments >= cmp::max(1, self.config.gc_min_segments as usize)
+    }
+
+    pub fn sweep_expired(&self, channel_name: &str, clock: u32) -> QueueResult<usize> {
+        if let Some(channel) = self.channels.get(channel_name) {
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.update_state(clock);
+            Ok(locked_channel.expired_count as usize)
+        } else {
+            Err(QueueError::ChannelNotFound)
+        }
+    }
+
+    pub fn maintenance(&self, clock: u32) {
+        let head = self.backend.head();
+        let max_lag = self.config.max_channel_lag;
+        for (channel_name, channel) in &self.channels {
+            let mut locked_channel = channel.lock().unwrap();
+            locked_channel.update_state(clock);
+            // expired messages at the tail won't be delivered, so they don't hold back gc
+            while let Some(message) = self.backend.get(cmp::max(locked_channel.tail, self.backend.tail())) {
+                if !message.is_expired(clock) {
+                    break
+                }
+                locked_channel.tail = message.id() + 1;
+            }
+            let tail = locked_channel.tail;
+            locked_channel.sent_ahead = locked_channel.sent_ahead.split_off(&tail);
+            // neither do the ones that timed out and expired while in flight,
+            // the others must be sent again so they keep holding back gc until then,
+            // releasing their segments would drop them, see channel_ttl and max_channel_lag
+            let dropped = locked_channel.drop_expired_in_flight(&self.backend, clock);
+            if dropped != 0 {
+                debug!("[{}:{}] dropped {} expired messages in flight",
+                    self.config.name, &channel_name[..], dropped);
+            }
+            let real_tail = locked_channel.real_tail();
+            let lag = head.saturating_sub(real_tail);
+            if max_lag != 0 && lag > max_lag {
+                let new_tail = head - max_lag;
+                self.backend.recent_errors().warn(format!(
+                    "[{}:{}] lagging {} messages behind, dropping {} messages",
+                    self.config.name, &channel_name[..], lag, new_tail - real_tail));
+                locked_channel.truncate(new_tail);
+            }
+        }
+        let smallest_tail = self.smallest_tail();
 
         debug!("[{}] smallest_tail is {}", self.config.name, smallest_tail);
 
         self.backend.gc(smallest_tail, clock);
-        self.as_mut().checkpoint(false);
+        self.check_watermarks();
+        // tails may have been moved by other means in the meantime
+        self.notify_drained();
+        self.checkpoint(false);
     }
+}
 
-    #[allow(mutable_transmutes)]
-    pub fn as_mut(&self) -> &mut Self {
-        unsafe { mem::transmute(self) }
+impl fmt::Debug for Watermarks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watermarks {{ high: {}, low: {}, paused: {} }}", self.high, self.low, self.paused)
     }
 }
 
 impl Drop for InnerQueue {
     fn drop(&mut self) {
-        if self.state != QueueState::Deleting {
+        if self.state != QueueState::Deleting && !self.backend.is_closed() {
             self.checkpoint(true)
         }
     }
@@ -594,7 +2300,7 @@ impl Drop for InnerQueue {
 mod tests {
     use super::*;
     use config::*;
-    use std::thread;
+    use std::{mem, thread};
     use test;
 
     fn get_queue_opt(name: &str, recover: bool) -> Queue {
@@ -605,7 +2311,7 @@ mod tests {
         server_config.default_queue_config.hard_retention_period = 2;
         let mut queue_config = server_config.new_queue_config(name);
         queue_config.message_timeout = 1;
-        Queue::new(queue_config, recover)
+        Queue::new(queue_config, recover).unwrap()
     }
 
     fn get_queue() -> Queue {
@@ -616,6 +2322,12 @@ mod tests {
         get_queue_opt(thread::current().name().unwrap(), true)
     }
 
+    fn take_dead_letters(q: &Queue) -> Vec<(String, Vec<u8>)> {
+        let mut dead_letters = Vec::new();
+        q.forward_dead_letters(|queue_name, body| dead_letters.push((queue_name.into(), body.to_vec())));
+        dead_letters
+    }
+
     fn gen_message() -> &'static [u8] {
         return b"333333333333333333333333333333333333333333333333333333333333333333333333333333333333333333333333";
     }
@@ -636,7 +2348,7 @@ mod tests {
         for _ in 0..100_000 {
             q.push(&message, 0).unwrap();
             let r = q.get("test", 0);
-            assert!(r.unwrap().1.body() == message);
+            assert!(r.unwrap().1.body() == Some(message));
         }
     }
 
@@ -682,7 +2394,7 @@ mod tests {
             q.push(gen_message(), 0).unwrap();
             put_msg_count += 1;
         }
-        q.checkpoint(true);
+        q.close().unwrap();
 
         q = get_queue_recover();
         assert_eq_repr!(q.create_channel("test", 1).unwrap_err(), QueueError::ChannelAlreadyExists);
@@ -703,7 +2415,7 @@ mod tests {
         q.get("test", 0).unwrap();
         q.get("test", 0).unwrap();
         q.get("test", 0).unwrap_err();
-        q.checkpoint(true);
+        q.close().unwrap();
 
         q = get_queue_recover();
         assert_eq_repr!(q.create_channel("test", 0).unwrap_err(), QueueError::ChannelAlreadyExists);
@@ -713,35 +2425,1735 @@ mod tests {
     }
 
     #[test]
-    fn test_maintenance() {
+    fn test_close_reopen() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.close().unwrap();
+        for i in 0..50 {
+            let q = get_queue_recover();
+            q.push(gen_message(), 0).unwrap();
+            let ticket = {
+                let (ticket, message) = q.get("test", 0).unwrap();
+                assert_eq!(message.id(), i + 1);
+                ticket
+            };
+            q.ack("test", ticket, 0).unwrap();
+            q.close().unwrap();
+        }
+        let q = get_queue_recover();
+        assert_eq!(q.info(0).channels["test"].tail, 51);
+
+        // closing doesn't wait forever for messages still alive
+        q.push(gen_message(), 0).unwrap();
+        let message = q.get("test", 0).unwrap().1;
+        q.close().unwrap_err();
+        assert_eq!(message.id(), 51);
+    }
+
+    #[test]
+    fn test_fork() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let fork_dir = q.config_cloned().data_directory.with_extension("fork");
+        remove_dir_if_exist(&fork_dir).unwrap();
+        let fork = q.fork("test_fork_fork", &fork_dir).unwrap();
+        assert!(q.fork("test_fork_fork", &fork_dir).is_err());
+        assert_eq!(fork.name(), "test_fork_fork");
+        loop {
+            let ticket = match fork.get("test", 0) {
+                Ok((ticket, _)) => ticket,
+                Err(QueueError::EndOfQueue(_)) => break,
+                Err(error) => panic!("{:?}", error),
+            };
+            fork.ack("test", ticket, 0).unwrap();
+        }
+        fork.push(gen_message(), 0).unwrap();
+        let fork_info = fork.info(0);
+        assert_eq!(fork_info.channels["test"].tail, 11);
+        fork.close().unwrap();
+
+        let info = q.info(0);
+        assert_eq!(info.head, 11);
+        assert_eq!(info.channels["test"].tail, 1);
+        let (_, message) = q.get("test", 0).unwrap();
+        assert_eq!(message.id(), 1);
+    }
+
+    #[test]
+    fn test_status_json() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        q.get("a", 0).unwrap();
+        let status: QueueStatus = json::decode(&q.status_json(0)).unwrap();
+        assert_eq!(status.name, q.name());
+        assert_eq!(status.state, QueueState::Ready);
+        assert_eq!(status.head, q.info(0).head);
+        assert_eq!(status.channels.len(), 2);
+        assert_eq!(status.channels["a"].in_flight_count, 1);
+        assert_eq!(status.channels["a"].lag, 10);
+        assert_eq!(status.channels["b"].tail, 1);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..3 {
+            q.get("a", 0).unwrap();
+        }
+        let (ticket, _) = q.get("b", 0).unwrap();
+        q.ack("b", ticket, 0).unwrap();
+        q.get("b", 0).unwrap();
+        assert_eq!(q.metrics(), QueueMetrics {
+            head: 11,
+            tail: 1,
+            files_count: 1,
+            channel_count: 2,
+            total_in_flight: 4,
+            state: QueueState::Ready,
+        });
+    }
+
+    #[test]
+    fn test_auto_ack_channel() {
+        let q = get_queue();
+        q.create_auto_ack_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let ticket = {
+            let mut ticket = 0;
+            for id in 1..11 {
+                let (t, message) = q.get("test", 0).unwrap();
+                assert_eq!(message.id(), id);
+                ticket = t;
+            }
+            ticket
+        };
+        assert_eq_repr!(q.get("test", 10).unwrap_err(), QueueError::EndOfQueue(11));
+        assert_eq_repr!(q.ack("test", ticket, 0).unwrap_err(), QueueError::TicketNotFound);
+        assert_eq!(q.info(0).channels["test"].in_flight_count, 0);
+        assert_eq!(q.inner.read().smallest_tail(), 11);
+        q.close().unwrap();
+
+        let q = get_queue_recover();
+        q.push(gen_message(), 0).unwrap();
+        q.get("test", 0).unwrap();
+        assert_eq!(q.inner.read().smallest_tail(), 12);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut q = get_queue();
+        let old_dir = q.config_cloned().data_directory;
+        let new_dir = old_dir.with_extension("renamed");
+        remove_dir_if_exist(&new_dir).unwrap();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let ticket = q.get("test", 0).unwrap().0;
+        q.rename("test_rename_renamed", &new_dir).unwrap();
+        assert_eq!(q.name(), "test_rename_renamed");
+        assert_eq!(q.config_cloned().name, "test_rename_renamed");
+        assert!(!old_dir.exists());
+        // in flight messages are kept
+        q.ack("test", ticket, 0).unwrap();
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+        q.push(gen_message(), 0).unwrap();
+        let config = q.config_cloned();
+        q.close().unwrap();
+
+        let mut q = Queue::new(config, true).unwrap();
+        assert_eq!(q.info(0).head, 12);
+        assert_eq!(q.info(0).channels["test"].tail, 2);
+        // the destination must not exist
+        assert!(q.rename("test_rename_renamed", &new_dir).is_err());
+        assert!(new_dir.exists());
+    }
+
+    #[test]
+    fn test_bytes_counters() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..5 {
+            q.get("test", 0).unwrap();
+        }
+        let info = q.info(0);
+        assert_eq!(info.bytes_produced, 10 * gen_message().len() as u64);
+        assert_eq!(info.bytes_delivered, 5 * gen_message().len() as u64);
+
+        // redeliveries are counted again
+        q.get("test", 1).unwrap();
+        assert_eq!(q.info(1).bytes_delivered, 6 * gen_message().len() as u64);
+    }
+
+    #[test]
+    fn test_health() {
+        let q = get_queue();
+        q.create_channel("stalled", 0).unwrap();
+        q.create_channel("active", 0).unwrap();
+        let health = q.health(5, 0);
+        assert!(health.writable);
+        assert_eq!(health.oldest_age, None);
+        assert_eq!(health.channels_behind, 0);
+
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+            let (ticket, _) = q.get("active", 0).unwrap();
+            q.ack("active", ticket, 0).unwrap();
+        }
+        let health = q.health(5, 10);
+        assert_eq!(health.state, QueueState::Ready);
+        assert_eq!(health.oldest_age, Some(10));
+        assert_eq!(health.channels_behind, 1);
+
+        q.set_draining(true);
+        let health = q.health(5, 10);
+        assert_eq!(health.state, QueueState::Draining);
+        assert!(!health.writable);
+        assert_eq_repr!(q.push(gen_message(), 10).unwrap_err(), QueueError::QueueDraining);
+        q.get("stalled", 10).unwrap();
+
+        q.set_draining(false);
+        assert!(q.health(5, 10).writable);
+        q.push(gen_message(), 10).unwrap();
+    }
+
+    #[test]
+    fn test_channel_dlq() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_retries = 2;
+        q.set_config(config);
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.create_channel("a_dlq", 0).unwrap();
+        q.set_channel_dlq("a", Some(DlqTarget::Channel("a_dlq".into()))).unwrap();
+        q.set_channel_dlq("b", Some(DlqTarget::Queue("other".into()))).unwrap();
+        assert_eq_repr!(q.set_channel_dlq("b", Some(DlqTarget::Channel("c".into()))).unwrap_err(),
+            QueueError::ChannelNotFound);
+        assert_eq_repr!(q.get("a_dlq", 0).unwrap_err(), QueueError::EndOfQueue(2));
+
+        // first delivery plus max_retries redeliveries
+        for clock in 0..3 {
+            assert_eq!(q.get("a", clock * 10).unwrap().1.id(), 1);
+            assert_eq!(q.get("b", clock * 10).unwrap().1.id(), 1);
+        }
+        assert_eq_repr!(q.get("a", 30).unwrap_err(), QueueError::EndOfQueue(2));
+        assert_eq_repr!(q.get("b", 30).unwrap_err(), QueueError::EndOfQueue(2));
+
+        let ticket = {
+            let (ticket, message) = q.get("a_dlq", 30).unwrap();
+            assert_eq!(message.id(), 1);
+            ticket
+        };
+        assert_eq_repr!(q.get("a_dlq", 30).unwrap_err(), QueueError::EndOfQueue(2));
+        q.ack("a_dlq", ticket, 30).unwrap();
+
+        let dead_letters = take_dead_letters(&q);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].0, "other");
+        assert_eq!(&dead_letters[0].1[..], gen_message());
+        assert!(take_dead_letters(&q).is_empty());
+
+        // targets are persisted
+        q.close().unwrap();
+        let q = get_queue_recover();
+        let mut config = q.config_cloned();
+        config.max_retries = 2;
+        q.set_config(config);
+        q.push(gen_message(), 30).unwrap();
+        for clock in 0..3 {
+            assert_eq!(q.get("b", 30 + clock * 10).unwrap().1.id(), 2);
+        }
+        q.get("b", 60).unwrap_err();
+        assert_eq!(take_dead_letters(&q).len(), 1);
+    }
+
+    #[test]
+    fn test_queue_dlq() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_retries = 2;
+        q.set_config(config.clone());
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+
+        // without any target the message is discarded
+        for clock in 0..3 {
+            let (ticket, message) = q.get("a", clock * 10).unwrap();
+            assert_eq!(message.id(), 1);
+            assert_eq!(q.retry_count("a", ticket).unwrap(), clock as u32);
+        }
+        assert_eq_repr!(q.get("a", 30).unwrap_err(), QueueError::EndOfQueue(2));
+        assert!(take_dead_letters(&q).is_empty());
+
+        // the queue wide target is used if the channel has none
+        config.dead_letter_queue = "dlq".into();
+        q.set_config(config);
+        for clock in 0..3 {
+            assert_eq!(q.get("b", clock * 10).unwrap().1.id(), 1);
+        }
+        assert_eq_repr!(q.get("b", 30).unwrap_err(), QueueError::EndOfQueue(2));
+        assert_eq_repr!(q.get("b", 40).unwrap_err(), QueueError::EndOfQueue(2));
+        let dead_letters = take_dead_letters(&q);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].0, "dlq");
+        assert!(take_dead_letters(&q).is_empty());
+        assert_eq_repr!(q.retry_count("b", 0).unwrap_err(), QueueError::TicketNotFound);
+    }
+
+    #[test]
+    fn test_dead_letters_persisted() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_retries = 1;
+        config.dead_letter_queue = "dlq".into();
+        q.set_config(config);
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        q.create_channel("a_dlq", 0).unwrap();
+        q.set_channel_dlq("a", Some(DlqTarget::Channel("a_dlq".into()))).unwrap();
+        for _ in 0..3 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..3 {
+            let ticket = q.get("a_dlq", 0).unwrap().0;
+            q.ack("a_dlq", ticket, 0).unwrap();
+        }
+        for &clock in &[0, 10] {
+            assert_eq!(q.get("a", clock).unwrap().1.id(), 1);
+            assert_eq!(q.get("b", clock).unwrap().1.id(), 1);
+        }
+        assert_eq!(q.get("a", 20).unwrap().1.id(), 2);
+        assert_eq!(q.get("b", 20).unwrap().1.id(), 2);
+
+        // neither is lost or sent again from the tail
+        q.close().unwrap();
+        let q = get_queue_recover();
+        assert_eq!(q.get("a_dlq", 20).unwrap().1.id(), 1);
+        assert_eq_repr!(q.get("a_dlq", 20).unwrap_err(), QueueError::EndOfQueue(4));
+        let dead_letters = take_dead_letters(&q);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].0, "dlq");
+        assert!(take_dead_letters(&q).is_empty());
+        assert_eq!(q.get("b", 20).unwrap().1.id(), 2);
+    }
+
+    #[test]
+    fn test_gc_advisable() {
         let q = get_queue();
         q.create_channel("test", 1).unwrap();
+        assert!(!q.gc_advisable());
 
-        while q.info(1).segments_count < 3 {
+        while q.info(1).segments_count < 2 {
             q.push(gen_message(), 1).unwrap();
-            let (ticket, _) = q.get("test", 1).unwrap();
+        }
+        assert!(!q.gc_advisable());
+        while let Ok((ticket, _)) = q.get("test", 1) {
             q.ack("test", ticket, 1).unwrap();
         }
+        assert!(q.gc_advisable());
 
-        // retention period will keep segments from beeing deleted
-        q.maintenance(2);
-        assert_eq!(q.info(2).segments_count, 3);
-        // retention period expired, gc should get rid of the first two segment
-        q.maintenance(3);
-        assert_eq!(q.info(3).segments_count, 1);
+        let mut config = q.config_cloned();
+        config.gc_min_segments = 2;
+        q.set_config(config);
+        assert!(!q.gc_advisable());
     }
 
-    #[bench]
-    fn put_like_crazy(b: &mut test::Bencher) {
+    #[test]
+    fn test_get_reader() {
         let q = get_queue();
-        let m = gen_message();
-        let n = 10000;
-        b.bytes = (m.len() * n) as u64;
-        b.iter(|| {
-            for _ in 0..n {
-                q.push(m, 0).unwrap();
+        q.create_channel("test", 0).unwrap();
+        let message: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        q.push(&message, 0).unwrap();
+
+        let (ticket, mut reader) = q.get_reader("test", 0).unwrap();
+        assert_eq!(reader.get_ref().id(), 1);
+        let mut read_message = Vec::new();
+        let mut buffer = [0u8; 1000];
+        loop {
+            let read = reader.read(&mut buffer).unwrap();
+            if read == 0 {
+                break
             }
-        });
+            read_message.extend_from_slice(&buffer[..read]);
+        }
+        assert!(read_message == message);
+        drop(reader);
+        q.ack("test", ticket, 0).unwrap();
+        assert_eq_repr!(q.get_reader("test", 0).unwrap_err(), QueueError::EndOfQueue(2));
+    }
+
+    #[test]
+    fn test_set_message_timeout() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.get("test", 0).unwrap();
+        q.set_message_timeout(10);
+        q.get("test", 0).unwrap();
+        // the first message keeps its timeout of 1
+        assert_eq!(q.info(1).channels["test"].in_flight_count, 1);
+        assert_eq!(q.info(9).channels["test"].in_flight_count, 1);
+        assert_eq!(q.info(10).channels["test"].in_flight_count, 0);
+        assert_eq!(q.config_cloned().message_timeout, 10);
+    }
+
+    #[test]
+    fn test_replay_to_channel() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        while let Ok((ticket, _)) = q.get("a", 0) {
+            q.ack("a", ticket, 0).unwrap();
+        }
+
+        assert_eq_repr!(q.replay_to_channel("b", 0, 0).unwrap_err(), QueueError::IdOutOfRange);
+        assert_eq_repr!(q.replay_to_channel("b", 12, 0).unwrap_err(), QueueError::IdOutOfRange);
+        q.replay_to_channel("b", 3, 0).unwrap();
+        assert_eq_repr!(q.replay_to_channel("b", 3, 0).unwrap_err(), QueueError::ChannelAlreadyExists);
+        for id in 3..11 {
+            assert_eq!(q.get("b", 0).unwrap().1.id(), id);
+        }
+        assert_eq_repr!(q.get("b", 0).unwrap_err(), QueueError::EndOfQueue(11));
+        assert_eq_repr!(q.get("a", 0).unwrap_err(), QueueError::EndOfQueue(11));
+    }
+
+    #[test]
+    fn test_seek_channel() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        while let Ok((ticket, _)) = q.get("a", 0) {
+            q.ack("a", ticket, 0).unwrap();
+        }
+
+        assert_eq_repr!(q.seek_channel("a", 0, 0).unwrap_err(), QueueError::IdOutOfRange);
+        assert_eq_repr!(q.seek_channel("a", 12, 0).unwrap_err(), QueueError::IdOutOfRange);
+        assert_eq_repr!(q.seek_channel("b", 3, 0).unwrap_err(), QueueError::ChannelNotFound);
+        q.seek_channel("a", 3, 0).unwrap();
+        let (ticket, message) = q.get("a", 0).unwrap();
+        assert_eq!(message.id(), 3);
+        drop(message);
+
+        // skipping ahead drops the in flight message
+        q.seek_channel("a", 8, 0).unwrap();
+        assert_eq_repr!(q.ack("a", ticket, 0).unwrap_err(), QueueError::TicketNotFound);
+        for id in 8..11 {
+            assert_eq!(q.get("a", 0).unwrap().1.id(), id);
+        }
+        assert_eq_repr!(q.get("a", 0).unwrap_err(), QueueError::EndOfQueue(11));
+    }
+
+    #[test]
+    fn test_clock_backwards() {
+        use std::sync::atomic::AtomicU64;
+        let last_clock = AtomicU64::new(0);
+        let clocks: Vec<u32> = [10u64, 5, 3, 9, 12].iter()
+            .map(|&c| clamp_monotonic(&last_clock, c) as u32)
+            .collect();
+        assert_eq!(clocks, vec![10, 10, 10, 10, 12]);
+
+        // the queue only sees the clamped clock, so the message gotten
+        // after the step back expires at 11 instead of 6
+        let q = get_queue();
+        q.create_channel("test", clocks[0]).unwrap();
+        q.push(gen_message(), clocks[0]).unwrap();
+        q.push(gen_message(), clocks[0]).unwrap();
+        assert_eq!(q.get_with_timeout("test", 2, clocks[0]).unwrap().1.id(), 1);
+        assert_eq!(q.get_with_timeout("test", 1, clocks[1]).unwrap().1.id(), 2);
+        for &clock in &clocks[2..4] {
+            assert_eq_repr!(q.get("test", clock).unwrap_err(), QueueError::EndOfQueue(3));
+        }
+        assert_eq!(q.get("test", clocks[4]).unwrap().1.id(), 2);
+        assert_eq!(q.get("test", clocks[4]).unwrap().1.id(), 1);
+    }
+
+    #[test]
+    fn test_drain_channel() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..25 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        {
+            let messages = q.drain_channel("a", true, 0).unwrap();
+            assert_eq!(messages.len(), 25);
+            assert_eq!(messages.iter().map(|m| m.id()).collect::<Vec<_>>(), (1..26).collect::<Vec<_>>());
+            let messages = q.drain_channel("b", false, 0).unwrap();
+            assert_eq!(messages.len(), 25);
+        }
+        assert_eq_repr!(q.get("a", 0).unwrap_err(), QueueError::EndOfQueue(26));
+        assert_eq!(q.info(0).channels["a"].in_flight_count, 0);
+        assert_eq!(q.info(0).channels["b"].in_flight_count, 25);
+        assert!(q.drain_channel("a", true, 0).unwrap().is_empty());
+        assert_eq_repr!(q.drain_channel("c", true, 0).unwrap_err(), QueueError::ChannelNotFound);
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.read_ahead = 4;
+        q.set_config(config);
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for id in 1..4 {
+            assert_eq!(q.get("test", 0).unwrap().1.id(), id);
+        }
+        // rewind and skip ahead
+        q.seek_channel_to_id("test", 2, 0).unwrap();
+        assert!(q.inner.read().channels["test"].lock().unwrap().read_ahead.is_none());
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+        q.seek_channel_to_id("test", 6, 0).unwrap();
+        for id in 6..11 {
+            assert_eq!(q.get("test", 0).unwrap().1.id(), id);
+        }
+        q.get("test", 0).unwrap_err();
+        q.push(gen_message(), 0).unwrap();
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 11);
+
+        // a read ahead doesn't hold segments
+        q.close().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_format() {
+        let q = get_queue();
+        assert_eq!(q.format_version(), FORMAT_VERSION);
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        let data_directory = q.config_cloned().data_directory;
+        q.close().unwrap();
+        // write the checkpoint the way the first version did
+        File::create(data_directory.join(QUEUE_CHECKPOINT_FILE)).unwrap()
+            .write_all(br#"{"state":"Ready","channels":{"test":{"tail":2,"last_touched":0}}}"#).unwrap();
+
+        let q = get_queue_recover();
+        assert_eq!(q.format_version(), 1);
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+        // older versions can't read those
+        assert_eq_repr!(q.push_expiring(gen_message(), 10, 0).unwrap_err(), QueueError::FormatUpgradeRequired(3));
+        assert_eq_repr!(q.push_delayed(gen_message(), 10, 0).unwrap_err(), QueueError::FormatUpgradeRequired(4));
+        assert_eq!(q.push(gen_message(), 0).unwrap(), 3);
+        assert_eq!(q.format_version(), 1);
+        // the version is kept if the checkpoint can't be written
+        let tmp_path = q.config_cloned().data_directory.join(TMP_QUEUE_CHECKPOINT_FILE);
+        fs::create_dir(&tmp_path).unwrap();
+        q.upgrade_format().unwrap_err();
+        assert_eq!(q.format_version(), 1);
+        fs::remove_dir(&tmp_path).unwrap();
+        q.upgrade_format().unwrap();
+        q.upgrade_format().unwrap();
+        assert_eq!(q.format_version(), FORMAT_VERSION);
+        assert_eq!(q.push_expiring(gen_message(), 10, 0).unwrap(), 4);
+        assert_eq!(q.push_delayed(gen_message(), 0, 0).unwrap(), 5);
+        q.close().unwrap();
+
+        let q = get_queue_recover();
+        assert_eq!(q.format_version(), FORMAT_VERSION);
+        for id in 2..6 {
+            assert_eq!(q.get("test", 0).unwrap().1.id(), id);
+        }
+        q.inner.lock().format_version = FORMAT_VERSION + 1;
+        q.upgrade_format().unwrap_err();
+        assert_eq!(q.format_version(), FORMAT_VERSION + 1);
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..3 {
+            q.get("test", 0).unwrap();
+        }
+        assert_eq!(q.sweep_expired("test", 0).unwrap(), 0);
+        assert_eq!(q.sweep_expired("test", 1).unwrap(), 3);
+        assert_eq!(q.info(1).channels["test"].in_flight_count, 0);
+        // gets take the expired ones first
+        assert_eq!(q.get("test", 1).unwrap().1.id(), 1);
+        assert_eq!(q.sweep_expired("test", 1).unwrap(), 2);
+        assert_eq!(q.sweep_expired("test", 2).unwrap(), 3);
+        // a longer timeout sent first doesn't hide the ones that expire before it
+        q.replay_to_channel("mixed", 1, 2).unwrap();
+        q.get_with_timeout("mixed", 10, 2).unwrap();
+        q.get("mixed", 2).unwrap();
+        q.get("mixed", 2).unwrap();
+        assert_eq!(q.sweep_expired("mixed", 3).unwrap(), 2);
+        assert_eq!(q.sweep_expired("mixed", 12).unwrap(), 3);
+        assert_eq_repr!(q.sweep_expired("nope", 2).unwrap_err(), QueueError::ChannelNotFound);
+    }
+
+    #[test]
+    fn test_auto_create_channels() {
+        let q = get_queue();
+        q.push(gen_message(), 0).unwrap();
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::ChannelNotFound);
+
+        let mut config = q.config_cloned();
+        config.auto_create_channels = true;
+        q.set_config(config);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(2));
+        assert!(q.info(0).channels.contains_key("test"));
+        q.push(gen_message(), 0).unwrap();
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+    }
+
+    #[test]
+    fn test_recent_errors() {
+        let q = get_queue();
+        q.checkpoint(false);
+        assert!(q.recent_errors().is_empty());
+        // a directory in the way makes writing the checkpoint fail
+        let tmp_path = q.config_cloned().data_directory.join(TMP_QUEUE_CHECKPOINT_FILE);
+        fs::create_dir(&tmp_path).unwrap();
+        q.checkpoint(false);
+        let errors = q.recent_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("error writing checkpoint information"));
+        fs::remove_dir(&tmp_path).unwrap();
+        q.checkpoint(false);
+        assert_eq!(q.recent_errors().len(), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_now() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let ticket = q.get("test", 0).unwrap().0;
+        q.ack("test", ticket, 0).unwrap();
+        q.checkpoint_now(true).unwrap();
+        let path = q.config_cloned().data_directory.join(QUEUE_CHECKPOINT_FILE);
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let checkpoint: QueueCheckpoint = json::decode(&contents).unwrap();
+        assert_eq!(checkpoint.channels["test"].tail, 2);
+
+        let tmp_path = q.config_cloned().data_directory.join(TMP_QUEUE_CHECKPOINT_FILE);
+        fs::create_dir(&tmp_path).unwrap();
+        assert!(q.checkpoint_now(false).is_err());
+        // returned, not recorded
+        assert!(q.recent_errors().is_empty());
+        fs::remove_dir(&tmp_path).unwrap();
+        q.checkpoint_now(false).unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..3 {
+            let ticket = q.get("a", 0).unwrap().0;
+            q.ack("a", ticket, 0).unwrap();
+        }
+        q.get("a", 0).unwrap();
+        q.get("b", 0).unwrap();
+        q.flush().unwrap();
+        q.flush().unwrap();
+        // skip the checkpoint on drop, as if the process was killed
+        mem::forget(q);
+
+        let q = get_queue_recover();
+        assert_eq!(q.info(0).head, 11);
+        assert_eq!(q.get("a", 0).unwrap().1.id(), 4);
+        assert_eq!(q.get("b", 0).unwrap().1.id(), 1);
+    }
+
+    #[test]
+    fn test_checkpoint_loop() {
+        let q = Arc::new(get_queue());
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..3 {
+            let ticket = q.get("test", 0).unwrap().0;
+            q.ack("test", ticket, 0).unwrap();
+        }
+        let handle = Queue::start_checkpoint_loop(&q, Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(1500));
+        let path = q.config_cloned().data_directory.join(QUEUE_CHECKPOINT_FILE);
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let checkpoint: QueueCheckpoint = json::decode(&contents).unwrap();
+        assert_eq!(checkpoint.channels["test"].tail, 4);
+
+        drop(q);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_consume_with() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let mut ids = Vec::new();
+        let consumed = q.consume_with("test", 4, 0, |message| {
+            ids.push(message.id());
+            if message.id() == 3 { Err(()) } else { Ok(()) }
+        }).unwrap();
+        assert_eq!(consumed, 3);
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(q.info(0).channels["test"].in_flight_count, 1);
+
+        // the failed one comes back after it times out
+        assert_eq!(q.consume_with("test", 10, 1, |_| Ok(())).unwrap(), 2);
+        assert_eq!(q.info(1).channels["test"].in_flight_count, 0);
+        assert_eq_repr!(q.get("test", 1).unwrap_err(), QueueError::EndOfQueue(6));
+    }
+
+    #[test]
+    fn test_ensure_channel() {
+        let q = get_queue();
+        assert!(!q.channel_exists("test"));
+        assert_eq!(q.ensure_channel("test", 0).unwrap(), ChannelCreation::Created);
+        assert!(q.channel_exists("test"));
+        assert_eq!(q.ensure_channel("test", 0).unwrap(), ChannelCreation::AlreadyExists);
+        assert_eq_repr!(q.create_channel("test", 0).unwrap_err(), QueueError::ChannelAlreadyExists);
+        q.delete_channel("test").unwrap();
+        assert!(!q.channel_exists("test"));
+    }
+
+    #[test]
+    fn test_delivery_order() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        let mut rng = JitterRng::new(0);
+        let mut pushed = 0;
+        let mut delivered = Vec::new();
+        let mut in_flight = Vec::new();
+        // random interleavings with no timeouts
+        for _ in 0..10000 {
+            match rng.next(2) {
+                0 => {
+                    q.push(gen_message(), 0).unwrap();
+                    pushed += 1;
+                }
+                1 => match q.get("test", 0) {
+                    Ok((ticket, message)) => {
+                        delivered.push(message.id());
+                        in_flight.push(ticket);
+                    }
+                    Err(QueueError::EndOfQueue(_)) => assert_eq!(delivered.len(), pushed),
+                    Err(error) => panic!("{:?}", error),
+                },
+                _ => if !in_flight.is_empty() {
+                    let i = rng.next(in_flight.len() as u32 - 1) as usize;
+                    let ticket = in_flight.swap_remove(i);
+                    q.ack("test", ticket, 0).unwrap();
+                },
+            }
+        }
+        while let Ok((_, message)) = q.get("test", 0) {
+            delivered.push(message.id());
+        }
+        // every id exactly once, in order
+        assert_eq!(delivered, (1..pushed as u64 + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_max_channel_lag() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_channel_lag = 10;
+        q.set_config(config);
+        q.create_channel("stalled", 0).unwrap();
+        q.create_channel("healthy", 0).unwrap();
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        // in flight and never acked
+        q.get("stalled", 0).unwrap();
+        for _ in 0..20 {
+            q.push(gen_message(), 0).unwrap();
+            let ticket = q.get("healthy", 0).unwrap().0;
+            q.ack("healthy", ticket, 0).unwrap();
+        }
+        q.maintenance(0);
+        let info = q.info(0);
+        assert_eq!(info.channels["stalled"].tail, 16);
+        assert_eq!(info.channels["stalled"].in_flight_count, 0);
+        assert_eq!(info.channels["healthy"].tail, 21);
+        assert_eq!(q.recent_errors().len(), 1);
+        assert_eq!(q.get("stalled", 0).unwrap().1.id(), 16);
+        assert_eq!(q.get("healthy", 0).unwrap().1.id(), 21);
+    }
+
+    #[test]
+    fn test_disk_quota() {
+        let q1 = get_queue_opt("test_disk_quota_1", false);
+        let q2 = get_queue_opt("test_disk_quota_2", false);
+        let segment_size = q1.config_cloned().segment_size;
+        let disk_quota = Arc::new(DiskQuota::new(3 * segment_size));
+        q1.set_disk_quota(disk_quota.clone());
+        q2.set_disk_quota(disk_quota.clone());
+        while q1.info(0).segments_count < 2 {
+            q1.push(gen_message(), 0).unwrap();
+        }
+        q2.push(gen_message(), 0).unwrap();
+        assert_eq!(disk_quota.used(), 3 * segment_size);
+        // q2 is small but can't get another segment
+        let error = loop {
+            if let Err(error) = q2.push(gen_message(), 0) {
+                break error
+            }
+        };
+        assert_eq_repr!(error, QueueError::Backend(QueueBackendError::DiskQuotaExceeded));
+        assert_eq!(q2.info(0).segments_count, 1);
+
+        q1.delete();
+        assert_eq!(disk_quota.used(), segment_size);
+        q2.push(gen_message(), 0).unwrap();
+        assert_eq!(disk_quota.used(), 2 * segment_size);
+    }
+
+    #[test]
+    fn test_expiring_message() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.create_channel("idle", 0).unwrap();
+        q.push_expiring(gen_message(), 5, 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push_expiring(b"expires later", 100, 0).unwrap();
+        {
+            let (_, message) = q.get("test", 0).unwrap();
+            assert_eq!(message.expires_at(), Some(5));
+            assert_eq!(message.body().unwrap(), gen_message());
+        }
+        q.seek_channel_to_id("test", 1, 0).unwrap();
+        // skipped once expired
+        assert_eq!(q.get("test", 10).unwrap().1.id(), 2);
+        {
+            let (_, message) = q.get("test", 10).unwrap();
+            assert_eq!(message.body().unwrap(), b"expires later");
+            assert_eq!(message.expires_at(), Some(100));
+        }
+        // and collectible even if never consumed
+        assert_eq!(q.info(10).channels["idle"].tail, 1);
+        q.maintenance(10);
+        assert_eq!(q.info(10).channels["idle"].tail, 2);
+        assert_eq!(q.inner.read().smallest_tail(), 2);
+        q.close().unwrap();
+
+        let q = get_queue_recover();
+        let (_, message) = q.get("idle", 10).unwrap();
+        assert_eq!(message.id(), 2);
+        assert_eq!(message.expires_at(), None);
+    }
+
+    #[test]
+    fn test_maintenance_drops_expired_in_flight() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.hard_retention_period = 0;
+        q.set_config(config);
+        q.create_channel("test", 0).unwrap();
+        q.push_expiring(gen_message(), 5, 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        // consumer goes away without acking
+        q.get("test", 0).unwrap();
+        while q.info(0).segments_count < 2 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        q.seek_channel_to_id("test", q.info(0).head, 0).unwrap();
+
+        // timed out but still valid, it has to be sent again
+        q.maintenance(3);
+        assert_eq!(q.inner.read().smallest_tail(), 1);
+        assert_eq!(q.info(3).segments_count, 2);
+
+        q.maintenance(10);
+        assert_eq!(q.channel_stats("test").unwrap().in_flight_count, 0);
+        assert_eq!(q.info(10).segments_count, 1);
+    }
+
+    #[test]
+    fn test_maintenance_keeps_timed_out_in_flight() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.hard_retention_period = 0;
+        q.set_config(config.clone());
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        // consumer goes away without acking
+        q.get("test", 0).unwrap();
+        while q.info(0).segments_count < 2 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        q.seek_channel_to_id("test", q.info(0).head, 0).unwrap();
+
+        // it's sent again by the next get, so it's kept no matter how long it's been
+        q.maintenance(1000);
+        assert_eq!(q.channel_stats("test").unwrap().in_flight_count, 1);
+        assert_eq!(q.inner.read().smallest_tail(), 1);
+        assert_eq!(q.info(1000).segments_count, 2);
+
+        config.max_channel_lag = 1;
+        q.set_config(config);
+        q.maintenance(1000);
+        assert_eq!(q.channel_stats("test").unwrap().in_flight_count, 0);
+        assert_eq!(q.info(1000).segments_count, 1);
+    }
+
+    #[test]
+    fn test_expire_idle_channels() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.channel_ttl = 10;
+        q.set_config(config);
+        q.create_channel("idle", 0).unwrap();
+        q.create_channel("busy", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.get("idle", 0).unwrap();
+        assert!(q.expire_idle_channels(10).is_empty());
+        q.get("busy", 5).unwrap();
+        assert_eq!(q.expire_idle_channels(11), vec!["idle".to_owned()]);
+        assert!(!q.channel_exists("idle"));
+        assert!(q.channel_exists("busy"));
+        q.close().unwrap();
+        let q = get_queue_recover();
+        assert!(!q.channel_exists("idle"));
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encryption() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        let mut config = q.config_cloned();
+        q.close().unwrap();
+        config.encryption = Some(EncryptionKey::from_hex(&"01".repeat(32)).unwrap());
+        let q = Queue::new(config.clone(), true).unwrap();
+        q.push(b"secret", 0).unwrap();
+        assert_eq!(q.format_version(), FORMAT_VERSION);
+        assert_eq!(q.get_by_id(1).unwrap().body().unwrap(), b"secret");
+        q.close().unwrap();
+
+        config.encryption = Some(EncryptionKey::from_hex(&"02".repeat(32)).unwrap());
+        let q = Queue::new(config, true).unwrap();
+        assert_eq_repr!(q.get_by_id(1).unwrap_err(), QueueError::DecryptionFailed(1));
+        assert_eq_repr!(q.peek("test", 0).unwrap_err(), QueueError::DecryptionFailed(1));
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::DecryptionFailed(1));
+        // it's kept in flight instead of being dropped
+        assert_eq!(q.channel_stats("test").unwrap().in_flight_count, 1);
+    }
+
+    #[test]
+    fn test_corrupt_message() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..3 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        assert!(q.verify_integrity().is_ok());
+        {
+            let message = q.get_by_id(2).unwrap();
+            unsafe { *(message.body().unwrap().as_ptr() as *mut u8) ^= 0xff };
+        }
+        assert_eq!(q.verify_integrity().unwrap_err(), vec![2]);
+        assert_eq_repr!(q.get_by_id(2).unwrap_err(), QueueError::CorruptMessage(2));
+
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 1);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::CorruptMessage(2));
+        assert_eq!(q.recent_errors().len(), 2);
+        // it's not sent again
+        assert_eq!(q.get("test", 10).unwrap().1.id(), 1);
+        assert_eq!(q.get("test", 10).unwrap().1.id(), 3);
+        assert_eq_repr!(q.get("test", 10).unwrap_err(), QueueError::EndOfQueue(4));
+    }
+
+    #[test]
+    fn test_get_batch_error_routes_dead_letters() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_retries = 1;
+        q.set_config(config);
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        q.set_channel_dlq("a", Some(DlqTarget::Channel("b".into()))).unwrap();
+        for _ in 0..3 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..2 {
+            let ticket = q.get("b", 0).unwrap().0;
+            q.ack("b", ticket, 0).unwrap();
+        }
+        for &clock in &[0, 10] {
+            assert_eq!(q.get("a", clock).unwrap().1.id(), 1);
+        }
+        {
+            let message = q.get_by_id(2).unwrap();
+            unsafe { *(message.body().unwrap().as_ptr() as *mut u8) ^= 0xff };
+        }
+        // msg 1 is dead lettered before the corrupt one is found
+        assert_eq_repr!(q.get_batch("a", 5, 20).unwrap_err(), QueueError::CorruptMessage(2));
+        assert_eq!(q.get("b", 20).unwrap().1.id(), 1);
+    }
+
+    #[test]
+    fn test_pause_channel() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..3 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let ticket = q.get("test", 0).unwrap().0;
+        q.pause_channel("test").unwrap();
+        assert_eq_repr!(q.pause_channel("other").unwrap_err(), QueueError::ChannelNotFound);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(2));
+        q.ack("test", ticket, 0).unwrap();
+        q.close().unwrap();
+
+        let q = get_queue_recover();
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(2));
+        q.resume_channel("test").unwrap();
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+    }
+
+    #[test]
+    fn test_get_by_id() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        let ids: Vec<_> = (0..5).map(|i| q.push(format!("message {}", i).as_bytes(), 0).unwrap()).collect();
+        assert_eq!(q.get_by_id(ids[2]).unwrap().body().unwrap(), b"message 2");
+        assert_eq_repr!(q.get_by_id(ids[4] + 1).unwrap_err(), QueueError::IdOutOfRange);
+        assert_eq_repr!(q.get_by_id(0).unwrap_err(), QueueError::IdOutOfRange);
+        q.truncate_to(ids[1]);
+        assert_eq_repr!(q.get_by_id(ids[0]).unwrap_err(), QueueError::IdOutOfRange);
+        assert_eq!(q.get_by_id(ids[1]).unwrap().body().unwrap(), b"message 1");
+        assert_eq!(q.info(0).channels["test"].tail, ids[1]);
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_in_flight = 5;
+        q.set_config(config);
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let tickets: Vec<_> = (0..5).map(|_| q.get("test", 0).unwrap().0).collect();
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(6));
+        q.ack("test", tickets[0], 0).unwrap();
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 6);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(7));
+        // timed out ones are still sent again
+        assert_eq!(q.get("test", 1).unwrap().1.id(), 2);
+        assert_eq!(q.get_batch("test", 10, 1).unwrap().len(), 4);
+        assert_eq_repr!(q.get("test", 1).unwrap_err(), QueueError::EndOfQueue(7));
+    }
+
+    #[test]
+    fn test_push_each() {
+        let q = get_queue();
+        let messages = vec![gen_message(); 1000];
+        let ids: Vec<u64> = q.push_each(&messages, 0).into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(ids, (1..1001).collect::<Vec<_>>());
+        assert_eq!(q.info(0).head, 1001);
+
+        q.set_draining(true);
+        let results = q.push_each(&messages[..2], 0);
+        assert_eq!(results.len(), 2);
+        for result in results {
+            assert_eq_repr!(result.unwrap_err(), QueueError::QueueDraining);
+        }
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.max_message_size = 10;
+        q.set_config(config);
+        assert_eq_repr!(q.push(b"more than ten bytes", 0).unwrap_err(),
+            QueueError::Backend(QueueBackendError::MessageTooBig));
+        assert_eq_repr!(q.push_many(&[b"small", b"more than ten bytes"], 0).unwrap_err(),
+            QueueError::Backend(QueueBackendError::MessageTooBig));
+        assert_eq!(q.info(0).head, 1);
+        let results = q.push_each(&[b"small", b"more than ten bytes"], 0);
+        assert_eq!(results[0].as_ref().unwrap(), &1);
+        assert!(results[1].is_err());
+        assert_eq!(q.push(b"ten bytes!", 0).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_priority_message() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push_priority(b"urgent", 9, 0).unwrap();
+        q.push_priority(gen_message(), 0, 0).unwrap();
+        q.push_priority(b"less urgent", 5, 0).unwrap();
+        let ids: Vec<u64> = (0..5).map(|_| {
+            let (ticket, message) = q.get("test", 0).unwrap();
+            let id = message.id();
+            drop(message);
+            q.ack("test", ticket, 0).unwrap();
+            id
+        }).collect();
+        assert_eq!(ids, vec![3, 5, 1, 2, 4]);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(6));
+        assert!(q.channel_is_drained("test").unwrap());
+    }
+
+    #[test]
+    fn test_delayed_message() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push_delayed(b"later", 2, 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.inner.write().push(b"both", Some(50), Some(3), 0).unwrap();
+        // the next ones are delivered in the meantime
+        let (ticket, message) = q.get("test", 0).unwrap();
+        assert_eq!(message.id(), 2);
+        drop(message);
+        q.ack("test", ticket, 0).unwrap();
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(4));
+        assert_eq_repr!(q.peek("test", 0).unwrap_err(), QueueError::EndOfQueue(4));
+        assert!(!q.channel_is_drained("test").unwrap());
+        let ticket = {
+            let (ticket, message) = q.get("test", 2).unwrap();
+            assert_eq!(message.id(), 1);
+            assert_eq!(message.body().unwrap(), b"later");
+            assert_eq!(message.deliver_at(), Some(2));
+            assert_eq!(message.expires_at(), None);
+            ticket
+        };
+        q.ack("test", ticket, 2).unwrap();
+        assert_eq_repr!(q.get("test", 2).unwrap_err(), QueueError::EndOfQueue(4));
+        let ticket = {
+            let (ticket, message) = q.get("test", 3).unwrap();
+            assert_eq!(message.id(), 3);
+            assert_eq!(message.body().unwrap(), b"both");
+            assert_eq!(message.deliver_at(), Some(3));
+            assert_eq!(message.expires_at(), Some(50));
+            ticket
+        };
+        q.ack("test", ticket, 3).unwrap();
+        assert!(q.channel_is_drained("test").unwrap());
+
+        // delayed messages hold the tail back
+        q.push_delayed(gen_message(), 20, 3).unwrap();
+        q.push(gen_message(), 3).unwrap();
+        let (ticket, message) = q.get("test", 10).unwrap();
+        assert_eq!(message.id(), 5);
+        drop(message);
+        q.ack("test", ticket, 10).unwrap();
+        q.close().unwrap();
+        let q = get_queue_recover();
+        assert_eq!(q.format_version(), FORMAT_VERSION);
+        // delayed messages are checkpointed, the ones after them aren't sent again
+        assert_eq!(q.info(10).channels["test"].tail, 6);
+        assert!(!q.channel_is_drained("test").unwrap());
+        assert_eq_repr!(q.get("test", 19).unwrap_err(), QueueError::EndOfQueue(6));
+        assert_eq!(q.get("test", 20).unwrap().1.id(), 4);
+    }
+
+    #[test]
+    fn test_channels_behind() {
+        let q = get_queue();
+        for channel_name in &["a", "b", "c"] {
+            q.create_channel(channel_name, 0).unwrap();
+        }
+        for clock in &[0, 10, 20, 30] {
+            q.push(gen_message(), *clock).unwrap();
+        }
+        for &(channel_name, count) in &[("b", 2), ("c", 4)] {
+            for _ in 0..count {
+                let ticket = q.get(channel_name, 30).unwrap().0;
+                q.ack(channel_name, ticket, 30).unwrap();
+            }
+        }
+        assert_eq!(q.channel_lag_secs("a", 40).unwrap(), 40);
+        assert_eq!(q.channel_lag_secs("c", 40).unwrap(), 0);
+        assert_eq_repr!(q.channel_lag_secs("d", 40).unwrap_err(), QueueError::ChannelNotFound);
+        assert_eq!(q.channels_behind(10, 40), vec![("a".to_owned(), 40), ("b".to_owned(), 20)]);
+        assert!(q.channels_behind(40, 40).is_empty());
+    }
+
+    #[test]
+    fn test_discover() {
+        let mut server_config = ServerConfig::read();
+        server_config.data_directory = "./test_data/test_discover".into();
+        server_config.default_queue_config.segment_size = 4 * 1024 * 1024;
+        let _ = fs::remove_dir_all(&server_config.data_directory);
+        for name in &["q1", "q2"] {
+            let q = Queue::new(server_config.new_queue_config(*name), false).unwrap();
+            q.create_channel("test", 0).unwrap();
+            q.push(gen_message(), 0).unwrap();
+        }
+        // half deleted queue
+        fs::create_dir_all(server_config.data_directory.join("q3")).unwrap();
+        // corrupted queue
+        fs::create_dir_all(server_config.data_directory.join("q4")).unwrap();
+        File::create(server_config.data_directory.join("q4").join(QUEUE_CHECKPOINT_FILE))
+            .unwrap().write_all(b"{").unwrap();
+
+        let mut queues = Queue::discover(&server_config);
+        assert_eq!(queues.iter().filter(|r| r.is_err()).count(), 1);
+        queues.retain(|r| r.is_ok());
+        let mut names: Vec<_> = queues.iter().map(|r| r.as_ref().unwrap().name().to_owned()).collect();
+        names.sort();
+        assert_eq!(names, vec!["q1".to_owned(), "q2".to_owned()]);
+        for q in queues {
+            let q = q.unwrap();
+            assert_eq!(q.get("test", 0).unwrap().1.id(), 1);
+        }
+    }
+
+    #[test]
+    fn test_channel_stats() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let tickets: Vec<i64> = (0..3).map(|_| q.get("a", 1).unwrap().0).collect();
+        let stats = q.channel_stats("a").unwrap();
+        // the messages in flight still count in the lag
+        assert_eq!(stats, ChannelStats{tail: 4, in_flight_count: 3, last_touched: 1, lag: 10});
+        let all_stats = q.all_channel_stats();
+        assert_eq!(all_stats.len(), 2);
+        assert_eq!(all_stats["a"], stats);
+        assert_eq!(all_stats["b"].lag, 10);
+        for ticket in tickets {
+            q.ack("a", ticket, 1).unwrap();
+        }
+        assert_eq!(q.channel_stats("a").unwrap().lag, 7);
+        assert_eq!(q.inner.read().status(1).channels["a"].lag, 7);
+        assert_eq_repr!(q.channel_stats("c").unwrap_err(), QueueError::ChannelNotFound);
+    }
+
+    #[test]
+    fn test_get_with_timeout() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        assert_eq!(q.get_with_timeout("test", 3, 0).unwrap().1.id(), 1);
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+        // message_timeout is 1
+        let (ticket, message) = q.get("test", 1).unwrap();
+        assert_eq!(message.id(), 2);
+        assert_eq_repr!(q.get("test", 1).unwrap_err(), QueueError::EndOfQueue(3));
+        assert_eq!(q.info(1).channels["test"].in_flight_count, 2);
+        q.ack("test", ticket, 1).unwrap();
+        assert_eq!(q.get("test", 3).unwrap().1.id(), 1);
+    }
+
+    #[test]
+    fn test_redelivery_backoff() {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.message_timeout = 10;
+        config.max_backoff = 50;
+        q.set_config(config);
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        // the long timeout of message 1 doesn't hold back message 2
+        assert_eq!(q.get_with_timeout("test", 1000, 0).unwrap().1.id(), 1);
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 2);
+        let mut clock = 0;
+        for &gap in &[10, 20, 40, 50, 50] {
+            assert_eq_repr!(q.get("test", clock + gap - 1).unwrap_err(), QueueError::EndOfQueue(3));
+            clock += gap;
+            assert_eq!(q.get("test", clock).unwrap().1.id(), 2);
+        }
+    }
+
+    #[test]
+    fn test_mixed_timeouts() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..4 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        // message_timeout is 1, the long timeout is in front of the short ones
+        q.get_with_timeout("test", 1000, 0).unwrap();
+        for _ in 0..3 {
+            q.get("test", 0).unwrap();
+        }
+        assert_eq!(q.sweep_expired("test", 1).unwrap(), 3);
+        assert_eq!(q.info(1).channels["test"].in_flight_count, 1);
+        assert_eq!(q.inner.read().channels["test"].lock().unwrap().messages_available(), 3);
+        assert_eq!(q.get("test", 1).unwrap().1.id(), 2);
+        assert_eq!(q.sweep_expired("test", 1000).unwrap(), 4);
+        assert_eq!(q.info(1000).channels["test"].in_flight_count, 0);
+    }
+
+    #[test]
+    fn test_touch() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        let (ticket, _) = q.get("test", 0).unwrap();
+        q.get("test", 0).unwrap();
+        q.touch("test", ticket, 5, 0).unwrap();
+        // message_timeout is 1
+        assert_eq!(q.get("test", 2).unwrap().1.id(), 2);
+        assert_eq_repr!(q.get("test", 2).unwrap_err(), QueueError::EndOfQueue(3));
+        assert_eq!(q.get("test", 5).unwrap().1.id(), 2);
+        assert_eq!(q.get("test", 5).unwrap().1.id(), 1);
+        assert_eq_repr!(q.touch("test", ticket, 5, 5).unwrap_err(), QueueError::TicketNotFound);
+    }
+
+    #[test]
+    fn test_touch_expired() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        let (ticket, _) = q.get_with_timeout("test", 1, 0).unwrap();
+        q.get_with_timeout("test", 10, 0).unwrap();
+        assert_eq!(q.info(2).channels["test"].in_flight_count, 1);
+        assert_eq_repr!(q.touch("test", ticket, 5, 2).unwrap_err(), QueueError::TicketNotFound);
+        assert_eq!(q.info(2).channels["test"].in_flight_count, 1);
+    }
+
+    #[test]
+    fn test_nack() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        let (ticket1, _) = q.get("test", 0).unwrap();
+        let (ticket2, _) = q.get("test", 0).unwrap();
+        // nacked messages come before older in flight ones
+        q.nack("test", ticket2, 0).unwrap();
+        assert_eq_repr!(q.nack("test", ticket2, 0).unwrap_err(), QueueError::TicketNotFound);
+        assert_eq!(q.info(0).channels["test"].in_flight_count, 1);
+        let (ticket2, message) = q.get("test", 0).unwrap();
+        assert_eq!(message.id(), 2);
+        assert_eq!(q.retry_count("test", ticket2).unwrap(), 1);
+        drop(message);
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 3);
+        // the tail doesn't move past nacked messages
+        q.ack("test", ticket1, 0).unwrap();
+        q.nack("test", ticket2, 0).unwrap();
+        q.checkpoint(false);
+        q.close().unwrap();
+        let q = get_queue_recover();
+        assert_eq!(q.info(0).channels["test"].tail, 2);
+    }
+
+    #[test]
+    fn test_peek() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        assert_eq_repr!(q.peek("test", 0).unwrap_err(), QueueError::EndOfQueue(1));
+        assert_eq_repr!(q.peek("missing", 0).unwrap_err(), QueueError::ChannelNotFound);
+        q.push(gen_message(), 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        for _ in 0..3 {
+            assert_eq!(q.peek("test", 5).unwrap().id(), 1);
+        }
+        assert_eq!(q.info(5).channels["test"].last_touched, 0);
+        assert_eq!(q.get("test", 5).unwrap().1.id(), 1);
+        assert_eq!(q.peek("test", 5).unwrap().id(), 2);
+        assert_eq!(q.get("test", 5).unwrap().1.id(), 2);
+        assert_eq_repr!(q.peek("test", 5).unwrap_err(), QueueError::EndOfQueue(3));
+    }
+
+    #[test]
+    fn test_get_batch() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        assert_eq_repr!(q.get_batch("test", 20, 0).unwrap_err(), QueueError::EndOfQueue(1));
+        for _ in 0..50 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let batch = q.get_batch("test", 20, 0).unwrap();
+        assert_eq!(batch.iter().map(|&(_, ref m)| m.id()).collect::<Vec<_>>(), (1..21).collect::<Vec<_>>());
+        drop(batch);
+        let batch = q.get_batch("test", 40, 0).unwrap();
+        assert_eq!(batch.iter().map(|&(_, ref m)| m.id()).collect::<Vec<_>>(), (21..51).collect::<Vec<_>>());
+        drop(batch);
+        assert_eq_repr!(q.get_batch("test", 20, 0).unwrap_err(), QueueError::EndOfQueue(51));
+        // timed out messages come first
+        let batch = q.get_batch("test", 5, 10).unwrap();
+        assert_eq!(batch.iter().map(|&(_, ref m)| m.id()).collect::<Vec<_>>(), (1..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reopen_deleting() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        q.push(gen_message(), 0).unwrap();
+        let config = q.config_cloned();
+        {
+            let mut inner = q.inner.lock();
+            inner.set_state(QueueState::Deleting);
+            inner.checkpoint(false);
+        }
+        q.close().unwrap();
+        assert!(config.data_directory.exists());
+        assert_eq_repr!(Queue::new(config.clone(), true).err().unwrap(), QueueError::WasDeleting);
+        assert!(!config.data_directory.exists());
+    }
+
+    #[test]
+    fn test_get_batch_ack() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..100 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let mut ids = Vec::new();
+        let mut tickets: Vec<_> = (0..10).map(|_| {
+            let (ticket, message) = q.get("test", 0).unwrap();
+            ids.push(message.id());
+            ticket
+        }).collect();
+        loop {
+            let batch = q.get_batch_ack("test", &tickets, 10, 0).unwrap();
+            if batch.is_empty() {
+                break
+            }
+            tickets = batch.iter().map(|&(ticket, _)| ticket).collect();
+            ids.extend(batch.iter().map(|&(_, ref message)| message.id()));
+            assert_eq!(q.info(0).channels["test"].in_flight_count, 10);
+        }
+        assert_eq!(ids, (1..101).collect::<Vec<_>>());
+        assert!(q.channel_is_drained("test").unwrap());
+        assert_eq_repr!(q.get_batch_ack("missing", &[], 1, 0).unwrap_err(), QueueError::ChannelNotFound);
+    }
+
+    #[test]
+    fn test_purge_count() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..100 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        assert_eq!(q.purge(), 100);
+        let info = q.info(0);
+        assert_eq!(info.head, info.tail);
+        assert_eq_repr!(q.get("test", 0).unwrap_err(), QueueError::EndOfQueue(info.head));
+        assert_eq!(q.purge(), 0);
+    }
+
+    #[test]
+    fn test_purge_nacked() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let (ticket, _) = q.get("test", 0).unwrap();
+        q.get_with_timeout("test", 1, 0).unwrap();
+        q.nack("test", ticket, 0).unwrap();
+        q.purge();
+        // nothing nacked or timed out survives the purge
+        assert_eq_repr!(q.get("test", 5).unwrap_err(), QueueError::EndOfQueue(11));
+        assert_eq!(q.info(5).channels["test"].in_flight_count, 0);
+        q.push(gen_message(), 5).unwrap();
+        assert_eq!(q.get("test", 5).unwrap().1.id(), 11);
+    }
+
+    #[test]
+    fn test_delete_count() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..42 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        q.get("test", 0).unwrap();
+        assert_eq!(q.delete(), 42);
+        assert!(!q.config_cloned().data_directory.exists());
+    }
+
+    #[test]
+    fn test_wait_empty() {
+        let q = Arc::new(get_queue());
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        assert!(q.wait_empty(Duration::from_millis(0)));
+        for _ in 0..5 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        q.drain_channel("a", true, 0).unwrap();
+        // b is stalled with everything in flight
+        let tickets: Vec<_> = (0..5).map(|_| q.get("b", 0).unwrap().0).collect();
+        assert!(q.channel_is_drained("a").unwrap());
+        assert!(!q.channel_is_drained("b").unwrap());
+        assert!(!q.wait_empty(Duration::from_millis(50)));
+
+        let consumer = {
+            let q = q.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                for ticket in tickets {
+                    q.ack("b", ticket, 0).unwrap();
+                }
+            })
+        };
+        assert!(q.wait_empty(Duration::from_secs(5)));
+        consumer.join().unwrap();
+        assert!(q.channel_is_drained("b").unwrap());
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let q = get_queue();
+        let signals = Arc::new(Mutex::new(Vec::new()));
+        let signals_cb = signals.clone();
+        q.set_backpressure_callback(Some(Arc::new(move |signal| signals_cb.lock().unwrap().push(signal))));
+        q.set_watermarks(5, 2);
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(&gen_message(), 0).unwrap();
+        }
+        assert_eq!(*signals.lock().unwrap(), vec![Backpressure::Pause]);
+        for _ in 0..7 {
+            let (ticket, _) = q.get("test", 0).unwrap();
+            q.ack("test", ticket, 0).unwrap();
+            q.maintenance(0);
+        }
+        assert_eq!(*signals.lock().unwrap(), vec![Backpressure::Pause]);
+        let (ticket, _) = q.get("test", 0).unwrap();
+        q.ack("test", ticket, 0).unwrap();
+        q.maintenance(0);
+        q.maintenance(0);
+        assert_eq!(*signals.lock().unwrap(), vec![Backpressure::Pause, Backpressure::Resume]);
+        q.push(&gen_message(), 0).unwrap();
+        assert_eq!(*signals.lock().unwrap(), vec![Backpressure::Pause, Backpressure::Resume]);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let q = get_queue();
+        q.create_channel("test", 1).unwrap();
+
+        while q.info(1).segments_count < 3 {
+            q.push(gen_message(), 1).unwrap();
+            let (ticket, _) = q.get("test", 1).unwrap();
+            q.ack("test", ticket, 1).unwrap();
+        }
+
+        // retention period will keep segments from beeing deleted
+        q.maintenance(2);
+        assert_eq!(q.info(2).segments_count, 3);
+        // retention period expired, gc should get rid of the first two segment
+        q.maintenance(3);
+        assert_eq!(q.info(3).segments_count, 1);
+    }
+
+    #[test]
+    fn test_redelivery_jitter() {
+        let get_expirations = |q: &Queue| {
+            let inner = q.inner.read();
+            let locked_channel = inner.channels["test"].lock().unwrap();
+            locked_channel.in_flight_map.values().map(|s| s.expiration).collect::<Vec<_>>()
+        };
+        let gen_expirations = |name: &str| {
+            let q = get_queue_opt(name, false);
+            let mut config = q.config_cloned();
+            config.redelivery_jitter = 100;
+            q.set_config(config);
+            *q.inner.read().jitter_rng.lock().unwrap() = JitterRng::new(1234);
+            q.create_channel("test", 0).unwrap();
+            q.push(gen_message(), 0).unwrap();
+            q.push(gen_message(), 0).unwrap();
+            q.get("test", 10).unwrap();
+            q.get("test", 10).unwrap();
+            get_expirations(&q)
+        };
+
+        let expirations = gen_expirations("test_redelivery_jitter_1");
+        assert_eq!(expirations.len(), 2);
+        assert!(expirations[0] != expirations[1]);
+        for &expiration in &expirations {
+            assert!(expiration >= 10 + 1 && expiration <= 10 + 1 + 100);
+        }
+        // same seed, same jitter
+        assert_eq!(gen_expirations("test_redelivery_jitter_2"), expirations);
+    }
+
+    #[test]
+    fn test_merge_channel() {
+        let q = get_queue();
+        q.create_channel("a", 0).unwrap();
+        q.create_channel("b", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..10 {
+            let (ticket, _) = q.get("a", 0).unwrap();
+            q.ack("a", ticket, 0).unwrap();
+        }
+        for _ in 0..3 {
+            let (ticket, _) = q.get("b", 0).unwrap();
+            q.ack("b", ticket, 0).unwrap();
+        }
+        // in flight in b, must be delivered again too
+        q.get("b", 0).unwrap();
+
+        assert_eq_repr!(q.merge_channel("a", "c", 0).unwrap_err(), QueueError::ChannelNotFound);
+        q.merge_channel("a", "b", 0).unwrap();
+        assert_eq_repr!(q.get("b", 0).unwrap_err(), QueueError::ChannelNotFound);
+        assert_eq!(q.info(0).channels.len(), 1);
+        for id in 4..11 {
+            assert_eq!(q.get("a", 0).unwrap().1.id(), id);
+        }
+        q.get("a", 0).unwrap_err();
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_events() {
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+        use tracing::{self, field, span, Event, Metadata, Subscriber};
+
+        struct OutcomeVisitor(Option<String>);
+
+        impl field::Visit for OutcomeVisitor {
+            fn record_str(&mut self, field: &field::Field, value: &str) {
+                if field.name() == "outcome" {
+                    self.0 = Some(value.into());
+                }
+            }
+
+            fn record_debug(&mut self, _: &field::Field, _: &fmt::Debug) {}
+        }
+
+        struct CaptureSubscriber(Arc<Mutex<Vec<String>>>);
+
+        impl Subscriber for CaptureSubscriber {
+            fn enabled(&self, _: &Metadata) -> bool { true }
+            fn new_span(&self, _: &span::Attributes) -> span::Id { span::Id::from_u64(1) }
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event) {
+                let mut visitor = OutcomeVisitor(None);
+                event.record(&mut visitor);
+                if let Some(outcome) = visitor.0 {
+                    self.0.lock().unwrap().push(outcome);
+                }
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(CaptureSubscriber(outcomes.clone()), || {
+            q.push(gen_message(), 0).unwrap();
+            let (ticket, _) = q.get("test", 0).unwrap();
+            q.get("test", 0).unwrap_err();
+            q.ack("test", ticket, 0).unwrap();
+            q.push(gen_message(), 0).unwrap();
+            q.get("test", 0).unwrap();
+            q.get("test", 1).unwrap();
+        });
+        assert_eq!(*outcomes.lock().unwrap(),
+            ["pushed", "fresh", "empty", "acked", "pushed", "fresh", "redelivery"]);
+    }
+
+    #[test]
+    fn test_truncate_to() {
+        let q = get_queue();
+        q.create_channel("test", 0).unwrap();
+        for _ in 0..10 {
+            q.push(gen_message(), 0).unwrap();
+        }
+        let (_, message) = q.get("test", 0).unwrap();
+        assert_eq!(message.id(), 1);
+        q.truncate_to(5);
+        assert_eq!(q.info(0).tail, 5);
+        assert_eq!(q.info(0).channels["test"].tail, 5);
+        assert_eq!(q.info(0).channels["test"].in_flight_count, 0);
+        assert_eq!(q.get("test", 0).unwrap().1.id(), 5);
+    }
+
+    #[bench]
+    fn put_like_crazy(b: &mut test::Bencher) {
+        let q = get_queue();
+        let m = gen_message();
+        let n = 10000;
+        b.bytes = (m.len() * n) as u64;
+        b.iter(|| {
+            for _ in 0..n {
+                q.push(m, 0).unwrap();
+            }
+        });
+    }
+
+    fn bench_get(b: &mut test::Bencher, read_ahead: u32) {
+        let q = get_queue();
+        let mut config = q.config_cloned();
+        config.read_ahead = read_ahead;
+        q.set_config(config);
+        let m = &gen_message();
+        let n = 10000;
+        q.create_channel("test", 0).unwrap();
+        b.bytes = (m.len() * n) as u64;
+        b.iter(|| {
+            for _ in 0..n {
+                q.push(m, 0).unwrap();
+            }
+            for _ in 0..n {
+                let (ticket, _) = q.get("test", 0).unwrap();
+                q.ack("test", ticket, 0).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    fn put_then_get_like_crazy(b: &mut test::Bencher) {
+        bench_get(b, 0)
+    }
+
+    #[bench]
+    fn put_then_get_like_crazy_read_ahead(b: &mut test::Bencher) {
+        bench_get(b, 16)
     }
 
     #[bench]
@@ -1,12 +1,16 @@
 use std::{ptr, slice, thread, cmp, io};
 use std::mem::{self, size_of};
 use std::io::prelude::*;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::hash::Hasher;
 use std::os::unix::io::{RawFd, AsRawFd};
 use std::fs::{self, File, OpenOptions};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::VecDeque;
+use std::vec;
 use nix::{self, c_void};
 use libc::size_t;
 use nix::sys::mman;
@@ -14,6 +18,11 @@ use spin::{Mutex as SpinLock, RwLock as SpinRwLock};
 use rustc_serialize::json;
 use twox_hash::XxHash;
 use fs2::FileExt;
+use rand::{self, Rng};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{AeadInPlace, KeyInit};
 
 use config::*;
 use utils::*;
@@ -21,6 +30,24 @@ use offset_index::*;
 
 const MAGIC_NUM: u32 = 0xF1031311u32;
 const INVALID_TIMESTAMP: u32 = 0;
+const RECENT_ERRORS_LEN: usize = 32;
+// set in MessageHeader.len when the body is preceded by an expiration timestamp
+const EXPIRES_FLAG: u32 = 1 << 31;
+// set in MessageHeader.len when the body is preceded by a delivery timestamp,
+// it follows the expiration timestamp if both are present
+const DELIVER_AT_FLAG: u32 = 1 << 30;
+// set in MessageHeader.len when the body is encrypted, the nonce and tag follow the timestamps
+const ENCRYPTED_FLAG: u32 = 1 << 29;
+const LEN_FLAGS: u32 = EXPIRES_FLAG | DELIVER_AT_FLAG | ENCRYPTED_FLAG;
+// the flags share MessageHeader.len, so the prefix and body must stay below them
+const MAX_DATA_LEN: u64 = ENCRYPTED_FLAG as u64 - 1;
+// AES-GCM nonce and tag
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// number of messages an adaptive segment is sized for
+const ADAPTIVE_SEGMENT_MESSAGES: u64 = 64 * 1024;
+// how long close waits for outstanding messages to be dropped
+const CLOSE_TIMEOUT_MS: u64 = 1000;
 
 pub type QueueBackendResult<T> = Result<T, QueueBackendError>;
 
@@ -29,6 +56,7 @@ pub enum QueueBackendError {
     MessageTooBig,
     SegmentFull,
     SegmentFileInvalid,
+    DiskQuotaExceeded,
     Io(io::Error),
 }
 
@@ -53,9 +81,19 @@ struct InnerMessage {
 pub struct Message {
     segment: Arc<Segment>,
     inner: InnerMessage,
+    // body of encrypted messages, only set once decrypted (see `decrypt`)
+    decrypted: Option<Vec<u8>>,
 }
 
 impl Message {
+    fn new(segment: Arc<Segment>, inner: InnerMessage) -> Message {
+        Message {
+            segment: segment,
+            inner: inner,
+            decrypted: None,
+        }
+    }
+
     pub fn id(&self) -> u64 {
         unsafe { (*self.inner.mmap_ptr).id }
     }
@@ -64,15 +102,99 @@ impl Message {
         unsafe { (*self.inner.mmap_ptr).timestamp }
     }
 
-    pub fn body(&self) -> &[u8] {
+    /// The body, None for encrypted messages that weren't decrypted (see `decrypt`)
+    pub fn body(&self) -> Option<&[u8]> {
+        if !self.is_encrypted() {
+            return Some(self.stored_body())
+        }
+        self.decrypted.as_ref().map(|decrypted| &decrypted[..])
+    }
+
+    /// Length of the body, encryption doesn't change it
+    pub fn body_len(&self) -> usize {
+        self.stored_body().len()
+    }
+
+    fn stored_body(&self) -> &[u8] {
         unsafe {
+            let header = &*self.inner.mmap_ptr;
+            let prefix_len = header.prefix_len() as usize;
             slice::from_raw_parts(
                 (self.inner.mmap_ptr as *const u8)
-                    .offset(size_of::<MessageHeader>() as isize),
-                (*self.inner.mmap_ptr).len as usize)
+                    .offset((size_of::<MessageHeader>() + prefix_len) as isize),
+                header.data_len() as usize - prefix_len)
         }
     }
 
+    /// Timestamp after which the message must not be delivered, if any
+    pub fn expires_at(&self) -> Option<u32> {
+        unsafe {
+            let header = &*self.inner.mmap_ptr;
+            if header.len & EXPIRES_FLAG == 0 {
+                return None
+            }
+            let data = (self.inner.mmap_ptr as *const u8).offset(size_of::<MessageHeader>() as isize);
+            Some(ptr::read_unaligned(data as *const u32))
+        }
+    }
+
+    pub fn is_expired(&self, clock: u32) -> bool {
+        self.expires_at().map_or(false, |expires_at| clock >= expires_at)
+    }
+
+    /// Timestamp before which the message must not be delivered, if any
+    pub fn deliver_at(&self) -> Option<u32> {
+        unsafe {
+            let header = &*self.inner.mmap_ptr;
+            if header.len & DELIVER_AT_FLAG == 0 {
+                return None
+            }
+            let offset = size_of::<MessageHeader>() + header.timestamps_len() as usize - size_of::<u32>();
+            let data = (self.inner.mmap_ptr as *const u8).offset(offset as isize);
+            Some(ptr::read_unaligned(data as *const u32))
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        unsafe { (*self.inner.mmap_ptr).len & ENCRYPTED_FLAG != 0 }
+    }
+
+    /// Makes the body available, returns false if the message was encrypted with another key
+    pub fn decrypt(&mut self) -> bool {
+        if !self.is_encrypted() || self.decrypted.is_some() {
+            return true
+        }
+        let key = if let Some(ref key) = self.segment.encryption {
+            key
+        } else {
+            return false
+        };
+        let (nonce, tag) = unsafe {
+            let header = &*self.inner.mmap_ptr;
+            let offset = size_of::<MessageHeader>() + header.timestamps_len() as usize;
+            let nonce = (self.inner.mmap_ptr as *const u8).offset(offset as isize);
+            (slice::from_raw_parts(nonce, NONCE_LEN),
+                slice::from_raw_parts(nonce.offset(NONCE_LEN as isize), TAG_LEN))
+        };
+        let mut body = self.stored_body().to_vec();
+        if decrypt_body(key, nonce, self.id(), &mut body, tag) {
+            self.decrypted = Some(body);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_delayed(&self, clock: u32) -> bool {
+        self.deliver_at().map_or(false, |deliver_at| clock < deliver_at)
+    }
+
+    /// Whether the stored hash still matches the message, it's only checked when recovering otherwise
+    pub fn is_intact(&self) -> bool {
+        let header = unsafe { &*self.inner.mmap_ptr };
+        header.hash == Segment::hash_segment_message(header)
+    }
+
     pub fn fd(&self) -> RawFd {
         self.segment.file.as_raw_fd()
     }
@@ -82,6 +204,12 @@ impl Message {
     }
 }
 
+impl AsRef<[u8]> for Message {
+    fn as_ref(&self) -> &[u8] {
+        self.body().expect("reading a message that wasn't decrypted")
+    }
+}
+
 unsafe impl Send for Message {}
 
 #[repr(packed)]
@@ -89,9 +217,173 @@ struct MessageHeader {
     hash: u32,
     id: u64,
     timestamp: u32,
+    // length of the data following the header, possibly flagged with LEN_FLAGS
     len: u32,
 }
 
+impl MessageHeader {
+    fn data_len(&self) -> u32 {
+        self.len & !LEN_FLAGS
+    }
+
+    /// bytes taken by the timestamps at the start of the data
+    fn timestamps_len(&self) -> u32 {
+        (self.len & (EXPIRES_FLAG | DELIVER_AT_FLAG)).count_ones() * size_of::<u32>() as u32
+    }
+
+    /// bytes before the body
+    fn prefix_len(&self) -> u32 {
+        let encryption_len = if self.len & ENCRYPTED_FLAG != 0 { NONCE_LEN + TAG_LEN } else { 0 };
+        self.timestamps_len() + encryption_len as u32
+    }
+}
+
+/// the message id is authenticated along with the body, so bodies can't be swapped around
+fn additional_data(id: u64) -> [u8; 8] {
+    unsafe { mem::transmute(id.to_le()) }
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt_body(key: &EncryptionKey, nonce: &[u8], id: u64, body: &mut [u8], tag: &mut [u8]) {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_bytes()));
+    let computed_tag = cipher.encrypt_in_place_detached(Nonce::from_slice(nonce), &additional_data(id), body)
+        .expect("Message too long to be encrypted");
+    tag.copy_from_slice(&computed_tag);
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt_body(key: &EncryptionKey, nonce: &[u8], id: u64, body: &mut [u8], tag: &[u8]) -> bool {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_bytes()));
+    cipher.decrypt_in_place_detached(Nonce::from_slice(nonce), &additional_data(id), body, Tag::from_slice(tag))
+        .is_ok()
+}
+
+#[cfg(not(feature = "encryption"))]
+fn encrypt_body(_: &EncryptionKey, _: &[u8], _: u64, _: &mut [u8], _: &mut [u8]) {
+    panic!("Encryption requires building with --features encryption")
+}
+
+#[cfg(not(feature = "encryption"))]
+fn decrypt_body(_: &EncryptionKey, _: &[u8], _: u64, _: &mut [u8], _: &[u8]) -> bool {
+    false
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueErrorRecord {
+    /// seconds since epoch
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// Keeps the latest errors around, so they can be inspected without the logs
+#[derive(Debug, Default)]
+pub struct RecentErrors(Mutex<VecDeque<QueueErrorRecord>>);
+
+impl RecentErrors {
+    /// log and keep an error
+    pub fn error(&self, message: String) {
+        error!("{}", message);
+        self.push(message);
+    }
+
+    /// log as a warning and keep an error
+    pub fn warn(&self, message: String) {
+        warn!("{}", message);
+        self.push(message);
+    }
+
+    fn push(&self, message: String) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut locked_errors = self.0.lock().unwrap();
+        if locked_errors.len() >= RECENT_ERRORS_LEN {
+            locked_errors.pop_front();
+        }
+        locked_errors.push_back(QueueErrorRecord {
+            timestamp: timestamp,
+            message: message,
+        });
+    }
+
+    /// oldest first
+    pub fn to_vec(&self) -> Vec<QueueErrorRecord> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Disk space shared by the segments of several queues, see `QueueBackend::set_disk_quota`
+#[derive(Debug)]
+pub struct DiskQuota {
+    // 0 means unlimited
+    limit: u64,
+    used: AtomicUsize,
+}
+
+impl DiskQuota {
+    pub fn new(limit: u64) -> DiskQuota {
+        DiskQuota {
+            limit: limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed) as u64
+    }
+
+    /// account for size if it fits in the limit
+    fn reserve(&self, size: u64) -> bool {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            if self.limit != 0 && used as u64 + size > self.limit {
+                return false
+            }
+            match self.used.compare_exchange(used, used + size as usize,
+                                             Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(prev) => used = prev,
+            }
+        }
+    }
+
+    /// account for size regardless of the limit
+    fn add(&self, size: u64) {
+        self.used.fetch_add(size as usize, Ordering::Relaxed);
+    }
+
+    fn release(&self, size: u64) {
+        self.used.fetch_sub(size as usize, Ordering::Relaxed);
+    }
+}
+
+/// Consecutive messages read in advance from a segment, see `QueueBackend::read_ahead`
+#[derive(Debug)]
+pub struct ReadAhead {
+    // doesn't keep the segment from being deleted
+    segment: Weak<Segment>,
+    messages: VecDeque<InnerMessage>,
+}
+
+impl ReadAhead {
+    /// Returns the next message if it has the specified id,
+    /// otherwise the read ahead is exhausted or no longer valid
+    pub fn next(&mut self, id: u64) -> Option<Message> {
+        let segment = if let Some(segment) = self.segment.upgrade() {
+            segment
+        } else {
+            self.messages.clear();
+            return None
+        };
+        if let Some(inner) = self.messages.pop_front() {
+            let message = Message::new(segment, inner);
+            if message.id() == id {
+                return Some(message)
+            }
+            self.messages.clear();
+        }
+        None
+    }
+}
+
 #[derive(Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
 struct SegmentCheckpoint {
     tail: u64,
@@ -102,7 +394,9 @@ struct SegmentCheckpoint {
 
 #[derive(Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
 struct QueueBackendCheckpoint {
-    segments: Vec<SegmentCheckpoint>
+    segments: Vec<SegmentCheckpoint>,
+    // only present if the tail was moved inside a segment (see truncate_to)
+    tail: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -112,25 +406,46 @@ pub struct Segment {
     file: File,
     file_mmap: *mut u8,
     file_len: u32,
-    file_offset: u32,
-    sync_offset: u32,
-    first_timestamp: u32,
-    last_timestamp: u32,
     tail: u64,
-    head: u64,
-    closed: bool,
-    deleted: bool,
+    // the ones below change while readers are active, the backend serializes pushes
+    // and the index lock is the sync point between them and the readers
+    file_offset: AtomicUsize,
+    sync_offset: AtomicUsize,
+    first_timestamp: AtomicUsize,
+    last_timestamp: AtomicUsize,
+    // number of messages, the head fits an AtomicUsize this way even on 32 bits
+    len: AtomicUsize,
+    closed: AtomicBool,
+    deleted: AtomicBool,
     index: SpinLock<OffsetIndex>,
+    // released when dropped
+    disk_quota: SpinLock<Option<Arc<DiskQuota>>>,
+    // new messages are encrypted with it, the existing ones may be either way
+    encryption: Option<EncryptionKey>,
     // dirty_bytes: usize,
     // dirty_messages: usize,
 }
 
+/// A segment data file and the ids it holds, from tail up to but excluding head
+#[derive(Debug, Clone)]
+pub struct SegmentInfo {
+    pub path: PathBuf,
+    pub tail: u64,
+    pub head: u64,
+    pub closed: bool,
+}
+
 #[derive(Debug)]
 pub struct QueueBackend {
     config: QueueConfig,
     segments: SpinRwLock<Vec<Arc<Segment>>>,
     head: u64,
-    tail: u64
+    // moved by gc while readers are active
+    tail: SpinLock<u64>,
+    last_sync: u32,
+    closed: bool,
+    recent_errors: RecentErrors,
+    disk_quota: Option<Arc<DiskQuota>>,
 }
 
 impl Segment {
@@ -143,13 +458,13 @@ impl Segment {
         unsafe {
             hasher.write(slice::from_raw_parts(
                 (header as *const _ as *const u8).offset(size_of::<u32>() as isize),
-                size_of::<MessageHeader>() + header.len as usize - size_of::<u32>()
+                size_of::<MessageHeader>() + header.data_len() as usize - size_of::<u32>()
             ));
         }
         hasher.finish() as u32
     }
 
-    fn create(config: &QueueConfig, start_id: u64) -> QueueBackendResult<Segment> {
+    fn create(config: &QueueConfig, start_id: u64, size: u64) -> QueueBackendResult<Segment> {
         let file_path = Self::gen_file_path(config, start_id, DATA_EXTENSION);
         debug!("[{}] creating data file {:?}", config.name, file_path);
         let file = try!(OpenOptions::new()
@@ -158,27 +473,28 @@ impl Segment {
                 .create(true)
                 .truncate(true)
                 .open(&file_path));
-        try!(file.allocate(config.segment_size));
+        try!(file.allocate(size));
 
-        let mut segment = try!(Self::new(config, file, file_path, start_id));
+        let segment = try!(Self::new(config, file, file_path, start_id));
         unsafe {
             *(segment.file_mmap as *mut u32) = MAGIC_NUM;
         }
-        segment.file_offset = size_of::<u32>() as u32;
+        segment.file_offset.store(size_of::<u32>(), Ordering::Relaxed);
 
         Ok(segment)
     }
 
-    fn open(config: &QueueConfig, checkpoint: &SegmentCheckpoint) -> QueueBackendResult<Segment> {
+    fn open(config: &QueueConfig, checkpoint: &SegmentCheckpoint, errors: &RecentErrors)
+            -> QueueBackendResult<Segment> {
         let file_path = Self::gen_file_path(config, checkpoint.tail, DATA_EXTENSION);
         debug!("[{}] opening data file {:?}", config.name, file_path);
         let file = try!(OpenOptions::new().read(true).write(true).open(&file_path));
         let mut segment = try!(Self::new(config, file, file_path, checkpoint.tail));
-        try!(segment.recover(checkpoint));
+        try!(segment.recover(checkpoint, errors));
         Ok(segment)
     }
 
-    fn new(_: &QueueConfig, file: File, file_path: PathBuf, start_id: u64) -> QueueBackendResult<Segment> {
+    fn new(config: &QueueConfig, file: File, file_path: PathBuf, start_id: u64) -> QueueBackendResult<Segment> {
         let file_len = try!(file.metadata()).len();
         if file_len <= 4 || file_len >= 1 << 31 {
             return Err(QueueBackendError::SegmentFileInvalid)
@@ -198,117 +514,188 @@ impl Segment {
             file: file,
             file_mmap: file_mmap,
             file_len: file_len as u32,
-            file_offset: 0,
-            sync_offset: 0,
-            first_timestamp: INVALID_TIMESTAMP,
-            last_timestamp: INVALID_TIMESTAMP,
             tail: start_id,
-            head: start_id,
-            closed: false,
-            deleted: false,
-            index: SpinLock::new(OffsetIndex::new(start_id))
+            file_offset: AtomicUsize::new(0),
+            sync_offset: AtomicUsize::new(0),
+            first_timestamp: AtomicUsize::new(INVALID_TIMESTAMP as usize),
+            last_timestamp: AtomicUsize::new(INVALID_TIMESTAMP as usize),
+            len: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            deleted: AtomicBool::new(false),
+            index: SpinLock::new(OffsetIndex::new(start_id)),
+            disk_quota: SpinLock::new(None),
+            encryption: config.encryption.clone(),
             // dirty_messages: 0,
             // dirty_bytes: 0,
         })
     }
-    
+
+    fn head(&self) -> u64 {
+        self.tail + self.len.load(Ordering::Relaxed) as u64
+    }
+
+    fn file_offset(&self) -> u32 {
+        self.file_offset.load(Ordering::Relaxed) as u32
+    }
+
+    fn first_timestamp(&self) -> u32 {
+        self.first_timestamp.load(Ordering::Relaxed) as u32
+    }
+
+    fn last_timestamp(&self) -> u32 {
+        self.last_timestamp.load(Ordering::Relaxed) as u32
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed)
+    }
+
     fn get(&self, id: u64) -> Result<InnerMessage, ()> {
         let message_offset = if let Some(message_offset) = self.index.lock().get_offset(id) {
             message_offset
         } else {
             return Err(())
         };
+        Ok(self.message_at(id, message_offset))
+    }
 
+    /// get up to count consecutive messages starting at id
+    fn get_many(&self, id: u64, count: usize) -> Vec<InnerMessage> {
+        let locked_index = self.index.lock();
+        let mut messages = Vec::with_capacity(count);
+        for id in id..id + count as u64 {
+            if let Some(message_offset) = locked_index.get_offset(id) {
+                messages.push(self.message_at(id, message_offset));
+            } else {
+                break
+            }
+        }
+        messages
+    }
+
+    fn message_at(&self, id: u64, message_offset: u32) -> InnerMessage {
         let header: &MessageHeader = unsafe {
             mem::transmute(self.file_mmap.offset(message_offset as isize))
         };
 
         // check id and possible overflow
-        assert!(message_offset <= self.file_offset,
-            "Corrupt file, message start offset {} is past file offset {}", message_offset, self.file_offset);
-        let message_end_offset = message_offset + size_of::<MessageHeader>() as u32 + header.len as u32;
-        assert!(message_end_offset <= self.file_offset,
-            "Corrupt file, message end offset {} is past file offset {}", message_end_offset, self.file_offset);
+        let file_offset = self.file_offset();
+        assert!(message_offset <= file_offset,
+            "Corrupt file, message start offset {} is past file offset {}", message_offset, file_offset);
+        let message_end_offset = message_offset + size_of::<MessageHeader>() as u32 + header.data_len();
+        assert!(message_end_offset <= file_offset,
+            "Corrupt file, message end offset {} is past file offset {}", message_end_offset, file_offset);
         assert!(header.id == id,
             "Corrupt file, ids don't match {} {} at offset {}", header.id, id, message_offset);
 
-        let message = InnerMessage {
+        InnerMessage {
             mmap_ptr: header,
-        };
-
-        Ok(message)
+        }
     }
 
-    fn push(&mut self, body: &[u8], clock: u32) -> QueueBackendResult<u64> {
+    fn push(&self, body: &[u8], clock: u32, expires_at: Option<u32>, deliver_at: Option<u32>)
+            -> QueueBackendResult<u64> {
         let header_size = size_of::<MessageHeader>() as u32;
-        let message_total_len = header_size + body.len() as u32;
+        let timestamps_len = (expires_at.iter().count() + deliver_at.iter().count()) * size_of::<u32>();
+        let encryption_len = if self.encryption.is_some() { NONCE_LEN + TAG_LEN } else { 0 };
+        let prefix_len = (timestamps_len + encryption_len) as u32;
+        if prefix_len as u64 + body.len() as u64 > MAX_DATA_LEN {
+            return Err(QueueBackendError::MessageTooBig)
+        }
+        let message_total_len = header_size + prefix_len + body.len() as u32;
+        let file_offset = self.file_offset();
 
-        if message_total_len > self.file_len - self.file_offset {
+        if message_total_len > self.file_len - file_offset {
             if message_total_len + size_of::<u32>() as u32 >= self.file_len {
                 return Err(QueueBackendError::MessageTooBig)
             }
-            self.closed = true;
+            self.close();
             return Err(QueueBackendError::SegmentFull)
         }
 
-        let id = self.head;
+        let id = self.head();
 
+        // past file_offset, so readers don't see it yet
         unsafe {
-            let header: &mut MessageHeader = mem::transmute(self.file_mmap.offset(self.file_offset as isize));
-            header.id = self.head;
+            let header: &mut MessageHeader = mem::transmute(self.file_mmap.offset(file_offset as isize));
+            header.id = id;
             header.timestamp = clock;
-            header.len = body.len() as u32;
-            ptr::copy_nonoverlapping(
-                body.as_ptr(),
-                self.file_mmap.offset(self.file_offset as isize + size_of::<MessageHeader>() as isize),
-                body.len());
+            header.len = prefix_len + body.len() as u32;
+            let data = self.file_mmap.offset(file_offset as isize + size_of::<MessageHeader>() as isize);
+            let mut prefix = data;
+            if let Some(expires_at) = expires_at {
+                header.len |= EXPIRES_FLAG;
+                ptr::write_unaligned(prefix as *mut u32, expires_at);
+                prefix = prefix.offset(size_of::<u32>() as isize);
+            }
+            if let Some(deliver_at) = deliver_at {
+                header.len |= DELIVER_AT_FLAG;
+                ptr::write_unaligned(prefix as *mut u32, deliver_at);
+                prefix = prefix.offset(size_of::<u32>() as isize);
+            }
+            let output = data.offset(prefix_len as isize);
+            ptr::copy_nonoverlapping(body.as_ptr(), output, body.len());
+            if let Some(ref key) = self.encryption {
+                header.len |= ENCRYPTED_FLAG;
+                let nonce = slice::from_raw_parts_mut(prefix, NONCE_LEN);
+                rand::thread_rng().fill_bytes(nonce);
+                let tag = slice::from_raw_parts_mut(prefix.offset(NONCE_LEN as isize), TAG_LEN);
+                encrypt_body(key, nonce, id, slice::from_raw_parts_mut(output, body.len()), tag);
+            }
             header.hash = Self::hash_segment_message(header);
         }
 
-        if self.first_timestamp == INVALID_TIMESTAMP {
-            self.first_timestamp = clock;
+        if self.first_timestamp() == INVALID_TIMESTAMP {
+            self.first_timestamp.store(clock as usize, Ordering::Relaxed);
         }
-        self.last_timestamp = clock;
-        self.file_offset += message_total_len;
-        self.head += 1;
+        self.last_timestamp.store(clock as usize, Ordering::Relaxed);
+        self.file_offset.store((file_offset + message_total_len) as usize, Ordering::Relaxed);
+        self.len.fetch_add(1, Ordering::Relaxed);
         // self.dirty_bytes += message_total_len;
         // self.dirty_messages += 1;
         // push_offset is the sync point between readers and writers, do it last
-        self.index.lock().push_offset(id, self.file_offset - message_total_len);
+        self.index.lock().push_offset(id, file_offset);
 
         Ok(id)
     }
 
-    fn sync(&mut self, full: bool) {
+    fn sync(&self, full: bool) -> io::Result<()> {
         let page_size = 4 * 1024;
         let slack_size = 1024 * 1024;
-        let sync_offset = if full || self.closed {
-            (self.file_offset + page_size - 1) & !(page_size - 1)
+        let file_offset = self.file_offset();
+        let sync_offset = if full || self.is_closed() {
+            (file_offset + page_size - 1) & !(page_size - 1)
         } else {
             // don't sync last 1MB to avoid neighbor page flushing and other side effects
             // like blocking writes to the page beeing flushed
-            self.file_offset.saturating_sub(slack_size) & !(page_size - 1)
+            file_offset.saturating_sub(slack_size) & !(page_size - 1)
         };
-        if sync_offset > 0 && sync_offset > self.sync_offset {
-            mman::msync(self.file_mmap as *mut c_void, sync_offset as size_t, mman::MS_SYNC).unwrap();
-            self.sync_offset = sync_offset;
+        // concurrent syncs may store a smaller offset, it only means syncing again next time
+        if sync_offset > 0 && sync_offset as usize > self.sync_offset.load(Ordering::Relaxed) {
+            try!(mman::msync(self.file_mmap as *mut c_void, sync_offset as size_t, mman::MS_SYNC));
+            self.sync_offset.store(sync_offset as usize, Ordering::Relaxed);
         }
+        Ok(())
     }
 
-    fn recover(&mut self, checkpoint: &SegmentCheckpoint) -> QueueBackendResult<()> {
+    fn recover(&mut self, checkpoint: &SegmentCheckpoint, errors: &RecentErrors) -> QueueBackendResult<()> {
         debug!("[{:?}] checkpoint loaded: {:?}", self.file_path, checkpoint);
         assert_eq!(self.tail, checkpoint.tail);
-        self.tail = checkpoint.tail;
-        self.head = checkpoint.tail;
-        self.sync_offset = checkpoint.sync_offset;
-        self.closed = checkpoint.closed;
+        self.closed.store(checkpoint.closed, Ordering::Relaxed);
 
         try!(mman::madvise(
             self.file_mmap as *mut c_void,
-            self.sync_offset as size_t,
+            checkpoint.sync_offset as size_t,
             mman::MADV_WILLNEED));
 
-        self.file_offset = size_of::<u32>() as u32;
+        let mut file_offset = size_of::<u32>() as u32;
+        let mut head = self.tail;
+        let mut first_timestamp = INVALID_TIMESTAMP;
+        let mut last_timestamp = INVALID_TIMESTAMP;
         unsafe {
             if *(self.file_mmap as *mut u32) != MAGIC_NUM {
                 warn!("[{:?}] incorrect magic number", self.file_path);
@@ -318,63 +705,62 @@ impl Segment {
 
         let header_size = size_of::<MessageHeader>() as u32;
         let mut locked_index = self.index.lock();
-        while self.file_offset + header_size < self.file_len {
+        while file_offset + header_size < self.file_len {
             let header: &MessageHeader = unsafe {
-                mem::transmute(self.file_mmap.offset(self.file_offset as isize))
+                mem::transmute(self.file_mmap.offset(file_offset as isize))
             };
-            let message_total_len = header_size + header.len;
-            if header.id != self.head {
+            let message_total_len = header_size + header.data_len();
+            if header.id != head {
                 warn!("[{:?}] expected id {} got {} when recovering @{}",
-                    self.file_path, self.head, header.id, self.file_offset);
+                    self.file_path, head, header.id, file_offset);
                 break
             }
-            if self.file_offset + message_total_len < self.file_len {
+            if file_offset + message_total_len < self.file_len {
                 if header.hash != Self::hash_segment_message(header) {
-                    warn!("[{:?}] corrupt message with id {} when recovering @{}",
-                        self.file_path, header.id, self.file_offset);
+                    errors.warn(format!("[{:?}] corrupt message with id {} when recovering @{}",
+                        self.file_path, header.id, file_offset));
                     break
                 }
             } else {
-                warn!("[{:?}] message with id {} would overflow file @{}",
-                    self.file_path, header.id, self.file_offset);
+                errors.warn(format!("[{:?}] message with id {} would overflow file @{}",
+                    self.file_path, header.id, file_offset));
                 break
             }
 
-            if self.first_timestamp == INVALID_TIMESTAMP {
-                self.first_timestamp = header.timestamp;
+            if first_timestamp == INVALID_TIMESTAMP {
+                first_timestamp = header.timestamp;
             }
-            self.last_timestamp = header.timestamp;
-            self.file_offset += header_size + header.len;
-            self.head += 1;
-            locked_index.push_offset(header.id, self.file_offset - message_total_len);
+            last_timestamp = header.timestamp;
+            locked_index.push_offset(header.id, file_offset);
+            file_offset += message_total_len;
+            head += 1;
         }
 
-        self.sync_offset = self.file_offset;
+        self.first_timestamp.store(first_timestamp as usize, Ordering::Relaxed);
+        self.last_timestamp.store(last_timestamp as usize, Ordering::Relaxed);
+        self.file_offset.store(file_offset as usize, Ordering::Relaxed);
+        self.sync_offset.store(file_offset as usize, Ordering::Relaxed);
+        self.len.store((head - self.tail) as usize, Ordering::Relaxed);
         Ok(())
     }
 
-    fn checkpoint(&mut self, full: bool) -> SegmentCheckpoint {
+    fn checkpoint(&self, full: bool) -> io::Result<SegmentCheckpoint> {
         // FIXME: reset and log stats
         let mut checkpoint = SegmentCheckpoint {
             tail: self.tail,
-            head: self.head,
-            sync_offset: self.sync_offset,
-            closed: self.closed,
+            head: self.head(),
+            sync_offset: self.sync_offset.load(Ordering::Relaxed) as u32,
+            closed: self.is_closed(),
         };
-        self.sync(full);
+        try!(self.sync(full));
         // update sync_offset
-        checkpoint.sync_offset = self.sync_offset;
-        checkpoint
+        checkpoint.sync_offset = self.sync_offset.load(Ordering::Relaxed) as u32;
+        Ok(checkpoint)
     }
 
-    fn purge(&mut self) {
-        assert!(!self.deleted);
-        self.deleted = true;
-    }
-
-    #[allow(mutable_transmutes)]
-    pub fn as_mut(&self) -> &mut Self {
-        unsafe { mem::transmute(self) }
+    fn purge(&self) {
+        let was_deleted = self.deleted.swap(true, Ordering::Relaxed);
+        assert!(!was_deleted);
     }
 }
 
@@ -383,9 +769,12 @@ impl Drop for Segment {
         let mmap = self.file_mmap as *mut c_void;
         mman::madvise(mmap, self.file_len as size_t, mman::MADV_DONTNEED).unwrap();
         mman::munmap(mmap, self.file_len as size_t).unwrap();
-        if self.deleted {
+        if self.deleted.load(Ordering::Relaxed) {
             remove_file_if_exist(&self.file_path).unwrap();
         }
+        if let Some(ref disk_quota) = *self.disk_quota.lock() {
+            disk_quota.release(self.file_len as u64);
+        }
     }
 }
 
@@ -395,7 +784,11 @@ impl QueueBackend {
             config: config,
             segments: SpinRwLock::new(Vec::new()),
             head: 1,
-            tail: 1
+            tail: SpinLock::new(1),
+            last_sync: 0,
+            closed: false,
+            recent_errors: Default::default(),
+            disk_quota: None,
         };
         if recover {
             backend.recover();
@@ -407,6 +800,24 @@ impl QueueBackend {
         self.config = config;
     }
 
+    /// Account the segments in disk_quota, new segments that don't fit fail with DiskQuotaExceeded
+    /// Note: the existing segments are accounted regardless of the limit
+    pub fn set_disk_quota(&mut self, disk_quota: Arc<DiskQuota>) {
+        for segment in self.segments.read().iter() {
+            let mut segment_quota = segment.disk_quota.lock();
+            if let Some(previous) = segment_quota.take() {
+                previous.release(segment.file_len as u64);
+            }
+            disk_quota.add(segment.file_len as u64);
+            *segment_quota = Some(disk_quota.clone());
+        }
+        self.disk_quota = Some(disk_quota);
+    }
+
+    pub fn recent_errors(&self) -> &RecentErrors {
+        &self.recent_errors
+    }
+
     pub fn segments_count(&self) -> usize {
         self.segments.read().len()
     }
@@ -416,7 +827,11 @@ impl QueueBackend {
     }
 
     pub fn tail(&self) -> u64 {
-        self.tail
+        *self.tail.lock()
+    }
+
+    fn set_tail(&self, tail: u64) {
+        *self.tail.lock() = tail
     }
 
     pub fn head(&self) -> u64 {
@@ -425,7 +840,8 @@ impl QueueBackend {
 
     fn find_segment(&self, id: u64) -> Option<Arc<Segment>> {
         for segment in self.segments.read().iter() {
-            if id < segment.head && segment.tail < segment.head {
+            let head = segment.head();
+            if id < head && segment.tail < head {
                 return Some(segment.clone())
             }
         }
@@ -435,15 +851,15 @@ impl QueueBackend {
     /// returns the smallest id that when requested will yield messages
     /// with timestamps >= the requested timestamp
     pub fn find_id_for_timestamp(&self, timestamp: u32) -> u64 {
-        let mut tail = self.tail;
+        let mut tail = self.tail();
         let mut head = self.head;
         // narrow search window using segment info, it may same some IO in the next step
         for segment in self.segments.read().iter() {
-            if segment.first_timestamp < timestamp {
+            if segment.first_timestamp() < timestamp {
                 tail = segment.tail
             }
-            if segment.last_timestamp > timestamp {
-                head = segment.head
+            if segment.last_timestamp() > timestamp {
+                head = segment.head()
             }
         }
         // binary search style search inside the segments
@@ -465,8 +881,25 @@ impl QueueBackend {
     /// Put a message at the end of the Queue, return the message id if succesfull
     /// Note: it's the caller responsability to serialize write calls
     pub fn push(&mut self, body: &[u8], timestamp: u32) -> QueueBackendResult<u64> {
+        self.push_expiring(body, timestamp, None)
+    }
+
+    /// Same as push but the message may expire, see `Message::expires_at`
+    pub fn push_expiring(&mut self, body: &[u8], timestamp: u32, expires_at: Option<u32>)
+            -> QueueBackendResult<u64> {
+        self.push_with(body, timestamp, expires_at, None)
+    }
+
+    /// Same as push but the message may expire and be delayed,
+    /// see `Message::expires_at` and `Message::deliver_at`
+    pub fn push_with(&mut self, body: &[u8], timestamp: u32, expires_at: Option<u32>, deliver_at: Option<u32>)
+            -> QueueBackendResult<u64> {
+        // checked here as well so no segment is created for it
+        if body.len() as u64 > MAX_DATA_LEN {
+            return Err(QueueBackendError::MessageTooBig)
+        }
         let result = if let Some(segment) = self.segments.read().last() {
-            segment.as_mut().push(body, timestamp)
+            segment.push(body, timestamp, expires_at, deliver_at)
         } else {
             Err(QueueBackendError::SegmentFull)
         };
@@ -481,45 +914,209 @@ impl QueueBackend {
             Err(_) => return result
         }
 
-        // create a new segment
-        let segment = Arc::new(try!(Segment::create(&self.config, self.head)));
+        // create a new segment, big enough for the message
+        let min_size = (3 * size_of::<u32>() + size_of::<MessageHeader>() + body.len()) as u64 + 1;
+        let segment_size = cmp::max(self.next_segment_size(), min_size);
+        if let Some(ref disk_quota) = self.disk_quota {
+            if !disk_quota.reserve(segment_size) {
+                return Err(QueueBackendError::DiskQuotaExceeded)
+            }
+        }
+        let segment = match Segment::create(&self.config, self.head, segment_size) {
+            Ok(segment) => segment,
+            Err(error) => {
+                self.disk_quota.as_ref().map(|q| q.release(segment_size));
+                return Err(error)
+            }
+        };
+        *segment.disk_quota.lock() = self.disk_quota.clone();
+        let segment = Arc::new(segment);
         self.segments.write().push(segment.clone());
 
-        let id = try!(segment.as_mut().push(body, timestamp));
+        let id = try!(segment.push(body, timestamp, expires_at, deliver_at));
         assert_eq!(id, self.head);
         self.head += 1;
         Ok(id)
     }
 
+    /// Size of the next segment to be created, with adaptive_segment it's based on
+    /// the average message size in the last segment
+    pub fn next_segment_size(&self) -> u64 {
+        if !self.config.adaptive_segment {
+            return self.config.segment_size
+        }
+        let average_size = if let Some(segment) = self.segments.read().last() {
+            if segment.head() == segment.tail {
+                return self.config.segment_size
+            }
+            (segment.file_offset() as u64 - size_of::<u32>() as u64) / (segment.head() - segment.tail)
+        } else {
+            return self.config.segment_size
+        };
+        let size = average_size * ADAPTIVE_SEGMENT_MESSAGES;
+        // whole pages
+        let size = (size + 4095) / 4096 * 4096;
+        cmp::min(cmp::max(size, self.config.min_segment_size), self.config.max_segment_size)
+    }
+
     /// Get a new message with the specified id
-    /// if not possible, return the next available message
+    /// if it falls in a missing segment, return the next available message
+    /// Note: ids below the tail or from the head onwards never return anything
     pub fn get(&self, id: u64) -> Option<Message> {
+        if id >= self.head || id < self.tail() {
+            return None
+        }
         if let Some(segment) = self.find_segment(id) {
             if let Ok(inner) = segment.get(cmp::max(id, segment.tail)) { 
-                return Some(Message {
-                    inner: inner,
-                    segment: segment,
-                })
+                return Some(Message::new(segment, inner))
+            }
+        }
+        None
+    }
+
+    /// Checks the hash of every retained message, returns the ids of the corrupt ones
+    /// Note: reads all the data, it's meant to be used after recovering from a crash
+    pub fn verify_integrity(&self) -> Result<(), Vec<u64>> {
+        let mut corrupt_ids = Vec::new();
+        let mut id = self.tail();
+        while let Some(message) = self.get(id) {
+            if !message.is_intact() {
+                corrupt_ids.push(message.id());
+            }
+            id = message.id() + 1;
+        }
+        if corrupt_ids.is_empty() {
+            Ok(())
+        } else {
+            Err(corrupt_ids)
+        }
+    }
+
+    /// Same as get but also reads up to count - 1 following messages in the same segment
+    pub fn read_ahead(&self, id: u64, count: usize) -> Option<(Message, ReadAhead)> {
+        let id = cmp::max(id, self.tail());
+        if let Some(segment) = self.find_segment(id) {
+            let mut messages: VecDeque<_> =
+                segment.get_many(cmp::max(id, segment.tail), count).into_iter().collect();
+            if let Some(inner) = messages.pop_front() {
+                let read_ahead = ReadAhead {
+                    segment: Arc::downgrade(&segment),
+                    messages: messages,
+                };
+                return Some((Message::new(segment, inner), read_ahead))
             }
         }
         None
     }
 
     pub fn purge(&mut self) {
-        self.tail = self.head;
-        self.segments.write().last_mut().map(|last| last.as_mut().closed = true);
+        self.set_tail(self.head);
+        self.segments.read().last().map(|last| last.close());
     }
 
-    fn wait_delete_segment(segment: Arc<Segment>) {
+    /// Sync the open segment to disk if sync_interval elapsed since the last time,
+    /// returns whether it synced
+    /// Note: it's the caller responsability to serialize write calls
+    pub fn sync_if_due(&mut self, clock: u32) -> io::Result<bool> {
+        if self.config.sync_interval == 0 || clock < self.last_sync + self.config.sync_interval {
+            return Ok(false)
+        }
+        try!(self.flush_active_segment());
+        self.last_sync = clock;
+        Ok(true)
+    }
+
+    /// Sync all data written to the segment being written to disk
+    /// Note: it's the caller responsability to serialize write calls
+    pub fn flush_active_segment(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.read().last() {
+            try!(segment.sync(true));
+        }
+        Ok(())
+    }
+
+    /// Drop all messages with ids smaller than the specified id,
+    /// segments left without messages are removed from disk
+    /// Note: it's the caller responsability to serialize write calls
+    pub fn truncate_to(&mut self, id: u64) {
+        let id = cmp::min(id, self.head);
+        if id <= self.tail() {
+            return
+        }
+        let mut dead_segments = Vec::new();
+        {
+            let mut locked_segments = self.segments.write();
+            // the open segment is kept even if empty, it's the one being written to
+            let dead_count = locked_segments.iter()
+                .take_while(|segment| segment.is_closed() && segment.head() <= id)
+                .count();
+            dead_segments.extend(locked_segments.drain(..dead_count));
+            self.set_tail(id);
+        }
+        info!("[{}] truncated to {}, {} segments removed",
+            self.config.name, id, dead_segments.len());
+        for segment in dead_segments {
+            Self::wait_delete_segment(segment)
+        }
+    }
+
+    fn wait_free_segment(segment: &Arc<Segment>) {
         let mut wait_count = 0;
-        while Arc::strong_count(&segment) > 1 {
+        while Arc::strong_count(segment) > 1 {
             thread::sleep(Duration::from_millis(100));
             wait_count += 1;
             if wait_count % 10 == 0 {
                 warn!("Still waiting for Segment {:?} to be freed {}", segment, wait_count);
             }
         }
-        segment.as_mut().purge()
+    }
+
+    fn wait_delete_segment(segment: Arc<Segment>) {
+        Self::wait_free_segment(&segment);
+        segment.purge()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Checkpoint and release all segments, unmapping and closing their files.
+    /// Outstanding messages hold a reference to their segment, so this waits for them to be dropped,
+    /// failing if they aren't within CLOSE_TIMEOUT_MS. Their files are then closed once they are.
+    /// The backend must not be used afterwards
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(())
+        }
+        self.checkpoint(true);
+        self.closed = true;
+        let segments = mem::replace(&mut *self.segments.write(), Vec::new());
+        let deadline = Instant::now() + Duration::from_millis(CLOSE_TIMEOUT_MS);
+        for segment in segments {
+            while Arc::strong_count(&segment) > 1 {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                        format!("Segment {:?} is still in use", segment)))
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the data directory to the one in config and open it again from there,
+    /// the rename itself is atomic so a crash leaves either directory valid.
+    /// Like close, it waits for outstanding messages to be dropped
+    pub fn rename(&mut self, config: QueueConfig) -> io::Result<()> {
+        let result = self.close();
+        let disk_quota = self.disk_quota.take();
+        let result = result.and_then(|_| fs::rename(&self.config.data_directory, &config.data_directory));
+        let config = if result.is_ok() { config } else { self.config.clone() };
+        *self = QueueBackend::new(config, true);
+        if let Some(disk_quota) = disk_quota {
+            self.set_disk_quota(disk_quota);
+        }
+        result
     }
 
     pub fn delete(&mut self) {
@@ -531,6 +1128,28 @@ impl QueueBackend {
         }
     }
 
+    /// Whether the segment is part of the checkpoint
+    fn is_checkpointed(&self, segment: &Segment) -> bool {
+        segment.tail >= self.tail() || segment.head() > self.tail() || !segment.is_closed()
+    }
+
+    /// Copy the data files and the checkpoint into directory so they can be opened as another queue,
+    /// closed segments are immutable so they're hardlinked if possible.
+    /// Pushes must be prevented while this runs.
+    pub fn snapshot_to(&mut self, directory: &Path) -> io::Result<()> {
+        self.checkpoint(true);
+        let segments_copy = self.segments.read().clone();
+        for segment in segments_copy.iter().filter(|s| self.is_checkpointed(s)) {
+            let target = directory.join(segment.file_path.file_name().unwrap());
+            if !segment.is_closed() || fs::hard_link(&segment.file_path, &target).is_err() {
+                try!(fs::copy(&segment.file_path, &target));
+            }
+        }
+        try!(fs::copy(self.config.data_directory.join(BACKEND_CHECKPOINT_FILE),
+            directory.join(BACKEND_CHECKPOINT_FILE)));
+        Ok(())
+    }
+
     fn recover(&mut self) {
         let path = self.config.data_directory.join(BACKEND_CHECKPOINT_FILE);
         let backend_checkpoint: QueueBackendCheckpoint = match File::open(path) {
@@ -541,8 +1160,8 @@ impl QueueBackend {
                 match checkpoint_result {
                     Ok(state) => state,
                     Err(error) => {
-                        error!("[{}] error parsing checkpoint information: {}",
-                            self.config.name, error);
+                        self.recent_errors.error(format!("[{}] error parsing checkpoint information: {}",
+                            self.config.name, error));
                         return;
                     }
                 }
@@ -558,77 +1177,106 @@ impl QueueBackend {
 
         let mut locked_segments = self.segments.write();
         for segment_checkpoint in &backend_checkpoint.segments {
-            let segment = match Segment::open(&self.config, segment_checkpoint) {
+            let segment = match Segment::open(&self.config, segment_checkpoint, &self.recent_errors) {
                 Ok(inner_segment) => Arc::new(inner_segment),
                 Err(error) => {
-                    warn!("[{}] error opening segment from checkpoint {:?}: {:?}",
-                        self.config.name, segment_checkpoint, error);
+                    self.recent_errors.warn(format!("[{}] error opening segment from checkpoint {:?}: {:?}",
+                        self.config.name, segment_checkpoint, error));
                     continue
                 },
             };
-            if !segment.closed && locked_segments.last_mut().is_some() {
+            if !segment.is_closed() {
                 // make sure we only have one open segment, the last
-                locked_segments.last_mut().unwrap().as_mut().closed = true;
+                locked_segments.last().map(|last| last.close());
             }
             locked_segments.push(segment);
         }
 
         if let Some(first_file) = locked_segments.first() {
-            self.tail = first_file.tail
+            self.set_tail(first_file.tail);
         }
 
         if let Some(last_file) = locked_segments.last() {
-            self.head = last_file.head;
+            self.head = last_file.head();
+        }
+
+        if let Some(tail) = backend_checkpoint.tail {
+            self.set_tail(cmp::min(cmp::max(self.tail(), tail), self.head));
+        }
+    }
+
+    pub fn checkpoint(&self, full: bool) {
+        if let Err(error) = self.try_checkpoint(full) {
+            self.recent_errors.error(format!("[{}] error writing checkpoint information: {}",
+                self.config.name, error));
         }
     }
 
-    pub fn checkpoint(&mut self, full: bool) {
+    /// Same as checkpoint but returns errors instead of recording them
+    pub fn try_checkpoint(&self, full: bool) -> io::Result<()> {
+        if self.closed {
+            return Ok(())
+        }
         let segments_copy = self.segments.read().clone();
-        let file_checkpoints: Vec<_> = segments_copy.into_iter().filter_map(|segment| {
-            if segment.tail >= self.tail {
-                Some(segment.as_mut().checkpoint(full))
-            } else {
-                None
-            }
-        }).collect();
+        let mut file_checkpoints = Vec::with_capacity(segments_copy.len());
+        for segment in segments_copy.iter().filter(|s| self.is_checkpointed(s)) {
+            file_checkpoints.push(try!(segment.checkpoint(full)));
+        }
 
+        let first_tail = file_checkpoints.first().map(|c| c.tail);
         let checkpoint = QueueBackendCheckpoint {
-            segments: file_checkpoints
+            segments: file_checkpoints,
+            tail: if first_tail.map_or(false, |t| t < self.tail()) { Some(self.tail()) } else { None },
         };
 
         let tmp_path = self.config.data_directory.join(TMP_BACKEND_CHECKPOINT_FILE);
-        let result = File::create(&tmp_path)
+        try!(File::create(&tmp_path)
             .and_then(|mut file| {
                 try!(write!(file, "{}", json::as_pretty_json(&checkpoint)));
                 file.sync_data()
             }).and_then(|_| {
                 let final_path = tmp_path.with_file_name(BACKEND_CHECKPOINT_FILE);
                 fs::rename(tmp_path, final_path)
-            });
+            }));
 
-        match result {
-            Ok(_) => {
-                info!("[{}] checkpointed: {:?}", self.config.name, checkpoint.segments);
-            }
-            Err(error) => {
-                panic!("[{}] error writing checkpoint information: {}",
-                    self.config.name, error);
-            }
-        }
+        info!("[{}] checkpointed: {:?}", self.config.name, checkpoint.segments);
+        Ok(())
+    }
+
+    /// The segments currently in use, in id order. Closed segments don't change anymore
+    /// so an incremental backup only needs the ones it doesn't have plus the open one
+    pub fn iter_segments(&self) -> vec::IntoIter<SegmentInfo> {
+        let segments: Vec<_> = self.segments.read().iter()
+            .filter(|s| self.is_checkpointed(s))
+            .map(|s| SegmentInfo {
+                path: s.file_path.clone(),
+                tail: s.tail,
+                head: s.head(),
+                closed: s.is_closed(),
+            })
+            .collect();
+        segments.into_iter()
+    }
+
+    /// number of leading segments with all messages before smallest_tail
+    pub fn consumed_segments(&self, smallest_tail: u64) -> usize {
+        self.segments.read().iter()
+            .take_while(|s| s.is_closed() && s.head() <= smallest_tail)
+            .count()
     }
 
-    pub fn gc(&mut self, smallest_tail: u64, clock: u32) {
+    pub fn gc(&self, smallest_tail: u64, clock: u32) {
         let mut gc_seg_count = 0;
         {
             let locked_segments = self.segments.read();
             let mut total_rem_size = locked_segments.iter().map(|s| s.file_len as u64).sum::<u64>();
             for segment in locked_segments.iter() {
-                if ! segment.closed {
+                if ! segment.is_closed() {
                     break
                 }
 
-                let contains_channel_msg = smallest_tail < segment.head;
-                let last_msg_age = clock - segment.last_timestamp;
+                let contains_channel_msg = smallest_tail < segment.head();
+                let last_msg_age = clock - segment.last_timestamp();
                 let pass_hard_retention_period =
                     self.config.hard_retention_period == 0 ||
                     last_msg_age <= self.config.hard_retention_period;
@@ -661,9 +1309,9 @@ impl QueueBackend {
                 let mut locked_segments = self.segments.write();
                 gc_segments.extend(locked_segments.drain(..gc_seg_count));
                 if let Some(first_segment) = locked_segments.first() {
-                    self.tail = first_segment.tail;
+                    self.set_tail(first_segment.tail);
                 } else {
-                    self.tail = self.head;
+                    self.set_tail(self.head);
                 }
             }
             for segment in gc_segments {
@@ -720,7 +1368,7 @@ mod tests {
             backend.get(backend.find_id_for_timestamp(timestamp)).unwrap().timestamp()
         };
 
-        assert_eq!(backend.find_id_for_timestamp(0), backend.tail);
+        assert_eq!(backend.find_id_for_timestamp(0), backend.tail());
         assert_eq!(backend.find_id_for_timestamp(num_writes as u32), backend.head);
         assert_eq!(get_timestamp(0), 1);
 
@@ -732,7 +1380,7 @@ mod tests {
         backend.segments.write().remove(1);
 
         for segment in backend.segments.read().iter() {
-            for ts in segment.tail as u32..segment.head as u32 {
+            for ts in segment.tail as u32..segment.head() as u32 {
                 assert_eq!(get_timestamp(ts), ts);
             }
         }
@@ -758,12 +1406,12 @@ mod tests {
     fn test_find_timestamp3() {
         let mut backend = get_backend();
         // when empty
-        assert_eq!(backend.find_id_for_timestamp(0), backend.tail);
-        assert_eq!(backend.find_id_for_timestamp(100000), backend.tail);
+        assert_eq!(backend.find_id_for_timestamp(0), backend.tail());
+        assert_eq!(backend.find_id_for_timestamp(100000), backend.tail());
         // simulate get misses
         backend.head = 10000;
-        assert_eq!(backend.find_id_for_timestamp(0), backend.tail);
-        assert_eq!(backend.find_id_for_timestamp(100000), backend.tail);
+        assert_eq!(backend.find_id_for_timestamp(0), backend.tail());
+        assert_eq!(backend.find_id_for_timestamp(100000), backend.tail());
     }
 
     #[test]
@@ -775,9 +1423,9 @@ mod tests {
         }
         let head = backend.head;
         // check segments last timestamps to validade the tests bellow
-        assert_eq!(backend.segments.read()[0].last_timestamp, 1);
-        assert_eq!(backend.segments.read()[1].last_timestamp, 2);
-        assert_eq!(backend.segments.read()[2].last_timestamp, 2);
+        assert_eq!(backend.segments.read()[0].last_timestamp(), 1);
+        assert_eq!(backend.segments.read()[1].last_timestamp(), 2);
+        assert_eq!(backend.segments.read()[2].last_timestamp(), 2);
 
         // retention period will keep segments from beeing deleted
         backend.gc(1, 2);
@@ -796,6 +1444,78 @@ mod tests {
         assert_eq!(backend.segments_count(), 1);
     }
 
+    #[test]
+    fn test_iter_segments() {
+        let mut backend = get_backend();
+        while backend.segments_count() < 3 {
+            let msg_timestamp = backend.segments_count() as u32;
+            backend.push(gen_message(), msg_timestamp).unwrap();
+        }
+        let ranges: Vec<_> = backend.iter_segments().map(|s| (s.tail, s.head)).collect();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].0, 1);
+        assert_eq!(ranges[2].1, backend.head);
+
+        let head = backend.head;
+        backend.gc(head, 3);
+        let segments: Vec<_> = backend.iter_segments().collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!((segments[0].tail, segments[0].head), ranges[1]);
+        assert_eq!((segments[1].tail, segments[1].head), ranges[2]);
+        assert!(segments[0].closed && !segments[1].closed);
+        assert!(segments.iter().all(|s| s.path.exists()));
+    }
+
+    #[test]
+    fn test_adaptive_segment() {
+        let mut backend = get_backend();
+        let mut config = backend.config.clone();
+        config.adaptive_segment = true;
+        config.min_segment_size = 1024 * 1024;
+        config.max_segment_size = 8 * 1024 * 1024;
+        backend.set_config(config);
+        assert_eq!(backend.next_segment_size(), backend.config.segment_size);
+
+        // pushes until count segments are created and returns the size of the last
+        fn fill_segments(backend: &mut QueueBackend, message: &[u8], count: usize) -> u64 {
+            let target = backend.segments_count() + count;
+            while backend.segments_count() < target {
+                backend.push(message, 0).unwrap();
+                let next_size = backend.next_segment_size();
+                assert!(next_size >= 1024 * 1024 && next_size <= 8 * 1024 * 1024);
+            }
+            let last_size = backend.segments.read().last().unwrap().file_len;
+            last_size as u64
+        }
+
+        let small_size = (gen_message().len() + size_of::<MessageHeader>()) as u64 * ADAPTIVE_SEGMENT_MESSAGES;
+        let small_size = (small_size + 4095) / 4096 * 4096;
+        let large_message = vec![0u8; 1024];
+        assert_eq!(fill_segments(&mut backend, gen_message(), 2), small_size);
+        assert_eq!(fill_segments(&mut backend, &large_message, 2), 8 * 1024 * 1024);
+        assert_eq!(fill_segments(&mut backend, gen_message(), 2), small_size);
+    }
+
+    #[test]
+    fn test_get_bounds() {
+        let mut backend = get_backend();
+        assert!(backend.get(0).is_none());
+        assert!(backend.get(1).is_none());
+        for _ in 0..10 {
+            backend.push(gen_message(), 0).unwrap();
+        }
+        let head = backend.head;
+        assert!(backend.get(head).is_none());
+        assert!(backend.get(head + 100).is_none());
+        assert!(backend.get(u64::max_value()).is_none());
+        assert_eq!(backend.get(head - 1).unwrap().id(), head - 1);
+        // as are the ones below the tail
+        backend.truncate_to(5);
+        assert!(backend.get(0).is_none());
+        assert!(backend.get(4).is_none());
+        assert_eq!(backend.get(5).unwrap().id(), 5);
+    }
+
     #[test]
     fn test_hard_retention_period() {
         let mut backend = get_backend();
@@ -808,6 +1528,134 @@ mod tests {
         assert_eq!(backend.segments_count(), 1);
     }
 
+    #[test]
+    fn test_sync_if_due() {
+        let mut backend = get_backend();
+        let mut config = backend.config.clone();
+        config.sync_interval = 5;
+        backend.set_config(config);
+        backend.push(gen_message(), 0).unwrap();
+        assert_eq!(backend.sync_if_due(1).unwrap(), false);
+        assert_eq!(backend.segments.read()[0].sync_offset.load(Ordering::Relaxed), 0);
+        assert_eq!(backend.sync_if_due(5).unwrap(), true);
+        assert!(backend.segments.read()[0].sync_offset.load(Ordering::Relaxed) > 0);
+        assert_eq!(backend.sync_if_due(6).unwrap(), false);
+        assert_eq!(backend.sync_if_due(10).unwrap(), true);
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encryption() {
+        let mut backend = get_backend();
+        let plain_id = backend.push(b"legacy plaintext", 0).unwrap();
+        let mut config = backend.config.clone();
+        backend.close().unwrap();
+
+        config.encryption = Some(EncryptionKey::from_hex(&"01".repeat(32)).unwrap());
+        let mut backend = QueueBackend::new(config.clone(), true);
+        // messages written without the key remain readable
+        assert!(!backend.get(plain_id).unwrap().is_encrypted());
+        assert_eq!(backend.get(plain_id).unwrap().body().unwrap(), b"legacy plaintext");
+        let id = backend.push_with(b"secret plaintext", 0, Some(10), Some(1)).unwrap();
+        {
+            let mut message = backend.get(id).unwrap();
+            assert!(message.is_encrypted() && message.is_intact());
+            // only decrypted when asked
+            assert_eq!(message.body(), None);
+            assert!(message.decrypt());
+            assert_eq!(message.body().unwrap(), b"secret plaintext");
+            assert_eq!(message.body_len(), 16);
+            assert_eq!(message.expires_at(), Some(10));
+            assert_eq!(message.deliver_at(), Some(1));
+        }
+        backend.flush_active_segment().unwrap();
+        let mut data = Vec::new();
+        File::open(&backend.segments.read()[0].file_path).unwrap().read_to_end(&mut data).unwrap();
+        assert!(data.windows(16).any(|w| w == b"legacy plaintext"));
+        assert!(!data.windows(16).any(|w| w == b"secret plaintext"));
+        backend.close().unwrap();
+
+        let backend = QueueBackend::new(config.clone(), true);
+        let mut message = backend.get(id).unwrap();
+        assert!(message.decrypt());
+        assert_eq!(message.body().unwrap(), b"secret plaintext");
+        drop(message);
+        drop(backend);
+
+        config.encryption = Some(EncryptionKey::from_hex(&"02".repeat(32)).unwrap());
+        let backend = QueueBackend::new(config, true);
+        let mut message = backend.get(id).unwrap();
+        assert!(message.is_intact());
+        assert!(!message.decrypt());
+        assert_eq!(message.body(), None);
+        assert!(backend.get(plain_id).unwrap().decrypt());
+    }
+
+    #[test]
+    fn test_push_errors() {
+        let mut backend = get_backend();
+        backend.push(gen_message(), 0).unwrap();
+        backend.flush_active_segment().unwrap();
+        assert!(backend.segments.read()[0].sync_offset.load(Ordering::Relaxed) > 0);
+
+        // rejected for lack of space
+        backend.set_disk_quota(Arc::new(DiskQuota::new(backend.config.segment_size)));
+        while let Ok(_) = backend.push(gen_message(), 0) {}
+        match backend.push(gen_message(), 0) {
+            Err(QueueBackendError::DiskQuotaExceeded) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // failing to create the next segment file
+        backend.set_disk_quota(Arc::new(DiskQuota::new(0)));
+        let data_directory = backend.config.data_directory.clone();
+        let mut config = backend.config.clone();
+        config.data_directory = data_directory.join("missing");
+        backend.set_config(config);
+        match backend.push(gen_message(), 0) {
+            Err(QueueBackendError::Io(ref error)) if error.kind() == io::ErrorKind::NotFound => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let head = backend.head;
+        assert_eq!(backend.segments_count(), 1);
+        assert!(backend.get(head - 1).is_some());
+    }
+
+    #[test]
+    fn test_truncate_to() {
+        let mut backend = get_backend();
+        while backend.segments_count() < 3 {
+            backend.push(gen_message(), 0).unwrap();
+        }
+        for _ in 0..10 {
+            backend.push(gen_message(), 0).unwrap();
+        }
+        let file_paths: Vec<_> = backend.segments.read().iter().map(|s| s.file_path.clone()).collect();
+        let third_tail = backend.segments.read()[2].tail;
+        let head = backend.head();
+
+        // no-op if not past the tail
+        let tail = backend.tail();
+        backend.truncate_to(tail);
+        assert_eq!(backend.segments_count(), 3);
+
+        backend.truncate_to(third_tail + 1);
+        assert_eq!(backend.segments_count(), 1);
+        assert_eq!(backend.tail(), third_tail + 1);
+        assert_eq!(backend.head(), head);
+        assert!(!file_paths[0].exists());
+        assert!(!file_paths[1].exists());
+        assert!(file_paths[2].exists());
+        assert!(backend.get(1).is_none());
+        assert_eq!(backend.get(third_tail + 1).unwrap().id(), third_tail + 1);
+
+        // tail survives recovery even if it points inside a segment
+        backend.checkpoint(true);
+        let backend = get_backend_recover();
+        assert_eq!(backend.tail(), third_tail + 1);
+        assert_eq!(backend.head(), head);
+    }
+
     #[test]
     fn test_corrupt_files() {
         let mut backend = get_backend();
@@ -820,12 +1668,12 @@ mod tests {
         {
             // zero second half of first file
             let mut segment = backend.segments.read()[0].clone();
-            segment.as_mut().file.set_len(segment.file_len as u64 / 2).unwrap();
-            segment.as_mut().file.set_len(segment.file_len as u64).unwrap();
+            segment.file.set_len(segment.file_len as u64 / 2).unwrap();
+            segment.file.set_len(segment.file_len as u64).unwrap();
             // nuke the second
             segment = backend.segments.read()[1].clone();
-            segment.as_mut().file.set_len(0).unwrap();
-            segment.as_mut().file.set_len(segment.file_len as u64).unwrap();
+            segment.file.set_len(0).unwrap();
+            segment.file.set_len(segment.file_len as u64).unwrap();
         }
 
         let mut backend = get_backend_recover();
@@ -846,7 +1694,7 @@ mod tests {
         {
             // truncate the third
             let segment = backend.segments.read()[2].clone();
-            segment.as_mut().file.set_len(0).unwrap();
+            segment.file.set_len(0).unwrap();
         }
 
         backend = get_backend_recover();
@@ -860,7 +1708,7 @@ mod tests {
             }
             id = message.id() + 1;
         }
-        assert_eq!(id, backend.segments.read()[0].head);
+        assert_eq!(id, backend.segments.read()[0].head());
         assert_eq!(num_reads, num_writes / 4);
         assert_eq!(holes, 0);
 
@@ -904,8 +1752,10 @@ mod tests {
 
             let backend = get_backend_recover();
             let mut num_reads = 0;
-            let mut id = 1;
-            let mut holes = 0;
+            // the ids of missing files at the start are below the tail, they're gone
+            let mut id = backend.tail();
+            let mut holes = if id != 1 { 1 } else { 0 };
+            assert!(id == 1 || backend.get(1).is_none());
             while let Some(message) = backend.get(id) {
                 num_reads += 1;
                 if message.id() != id {
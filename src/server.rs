@@ -5,6 +5,7 @@ use std::io::{Read, Write};
 use std::collections::VecDeque;
 use std::collections::hash_map::Entry;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
 use std::time::Duration;
 use spin::RwLock as SpinRwLock;
 use mio::tcp::{TcpStream, TcpListener};
@@ -15,6 +16,7 @@ use rustc_serialize::json;
 use promising_future::{future_promise, Promise};
 use num_cpus::get as get_num_cpus;
 use queue::*;
+use queue_backend::{QueueBackendError, DiskQuota};
 use config::*;
 use protocol::{Value, ProtocolError, RequestBuffer, ResponseBuffer};
 use utils::*;
@@ -25,6 +27,9 @@ use time;
 const SERVER: Token = Token(0);
 const FIRST_CLIENT: Token = Token(1);
 
+// shared by all threads so the clock never goes backwards
+static LAST_CLOCK_MS: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub enum NotifyMessage {
     Response{response: Value},
@@ -52,6 +57,20 @@ impl From<QueueError> for NotifyMessage {
                 NotifyMessage::with_error("QAE Queue Already Exists"),
             QueueError::ChannelAlreadyExists =>
                 NotifyMessage::with_error("CAE Channel Already Exists"),
+            QueueError::QueueDraining =>
+                NotifyMessage::with_error("QDR Queue Draining"),
+            QueueError::Backend(QueueBackendError::DiskQuotaExceeded) =>
+                NotifyMessage::with_error("DQE Disk Quota Exceeded"),
+            QueueError::CorruptMessage(_) =>
+                NotifyMessage::with_error("CRM Corrupt Message"),
+            QueueError::DecryptionFailed(_) =>
+                NotifyMessage::with_error("DEC Decryption Failed"),
+            QueueError::Backend(QueueBackendError::MessageTooBig) =>
+                NotifyMessage::with_error("MTB Message Too Big"),
+            QueueError::IdOutOfRange =>
+                NotifyMessage::with_error("IOR Id Out Of Range"),
+            QueueError::FormatUpgradeRequired(_) =>
+                NotifyMessage::with_error("FUR Format Upgrade Required"),
             _ => NotifyMessage::with_error(&format!("Unexpected error {:?}", from))
         }
     }
@@ -83,6 +102,7 @@ struct Dispatch {
     channel: mio::Sender<NotifyType>,
     meta_lock: Arc<Mutex<()>>,
     queues: Arc<SpinRwLock<HashMap<Atom, Arc<Queue>>>>,
+    disk_quota: Arc<DiskQuota>,
     clock: u32,
 }
 
@@ -105,6 +125,7 @@ pub struct Server {
     config: Arc<ServerConfig>,
     meta_lock: Arc<Mutex<()>>,
     queues: Arc<SpinRwLock<HashMap<Atom, Arc<Queue>>>>,
+    disk_quota: Arc<DiskQuota>,
     waiting_clients: HashMap<Atom, WaitingClients>,
     awaking_clients: Vec<(Token, Value)>,
     listener: TcpListener,
@@ -148,8 +169,38 @@ macro_rules! queue_config_set {
         match $k {
             "message_timeout" =>
                 $c.message_timeout = (try_or_error!(parse_duration($v), "IPA Invalid Duration Value")  / 1000) as u32,
+            "redelivery_jitter" =>
+                $c.redelivery_jitter = (try_or_error!(parse_duration($v), "IPA Invalid Duration Value")  / 1000) as u32,
+            "max_backoff" =>
+                $c.max_backoff = (try_or_error!(parse_duration($v), "IPA Invalid Duration Value")  / 1000) as u32,
+            "max_retries" =>
+                $c.max_retries = try_or_error!($v.parse::<u32>(), "IPA Invalid Integer Value"),
+            "dead_letter_queue" =>
+                $c.dead_letter_queue = $v.to_owned(),
+            "gc_min_segments" =>
+                $c.gc_min_segments = try_or_error!($v.parse::<u32>(), "IPA Invalid Integer Value"),
+            "max_channel_lag" =>
+                $c.max_channel_lag = try_or_error!($v.parse::<u64>(), "IPA Invalid Integer Value"),
+            "auto_create_channels" =>
+                $c.auto_create_channels = try_or_error!($v.parse::<bool>(), "IPA Invalid Boolean Value"),
+            "read_ahead" =>
+                $c.read_ahead = try_or_error!($v.parse::<u32>(), "IPA Invalid Integer Value"),
+            "max_in_flight" =>
+                $c.max_in_flight = try_or_error!($v.parse::<u32>(), "IPA Invalid Integer Value"),
+            "channel_ttl" =>
+                $c.channel_ttl = (try_or_error!(parse_duration($v), "IPA Invalid Duration Value")  / 1000) as u32,
+            "sync_interval" =>
+                $c.sync_interval = (try_or_error!(parse_duration($v), "IPA Invalid Duration Value")  / 1000) as u32,
             "segment_size" =>
                 $c.segment_size = try_or_error!(parse_size($v), "IPA Invalid Size Value"),
+            "adaptive_segment" =>
+                $c.adaptive_segment = try_or_error!($v.parse::<bool>(), "IPA Invalid Boolean Value"),
+            "min_segment_size" =>
+                $c.min_segment_size = try_or_error!(parse_size($v), "IPA Invalid Size Value"),
+            "max_segment_size" =>
+                $c.max_segment_size = try_or_error!(parse_size($v), "IPA Invalid Size Value"),
+            "max_message_size" =>
+                $c.max_message_size = try_or_error!(parse_size($v), "IPA Invalid Size Value"),
             "retention_size" =>
                 $c.retention_size = try_or_error!(parse_size($v), "IPA Invalid Size Value"),
             "hard_retention_size" =>
@@ -216,7 +267,8 @@ impl Dispatch {
             },
             Entry::Vacant(v) => {
                 info!("Creating queue {:?}", name);
-                let queue = Arc::new(Queue::new(self.config.new_queue_config(name), false));
+                let queue = Arc::new(try!(Queue::new(self.config.new_queue_config(name), false)));
+                queue.set_disk_quota(self.disk_quota.clone());
                 self.notify_server(NotifyMessage::QueueCreate{
                     queue: queue.name().into(),
                 });
@@ -264,16 +316,17 @@ impl Dispatch {
     fn create_channel(&self, q: &Queue, channel_name: &str, use_existing: bool) -> QueueResult<()> {
         info!("creating queue {:?} channel {:?}", q.name(), channel_name);
         let _meta_lock = self.meta_lock.lock().unwrap();
-        let result = q.create_channel(channel_name, self.clock);
-        if result.is_ok() {
-            self.notify_server(NotifyMessage::ChannelCreate{
-                queue: q.name().into(),
-                channel: channel_name.into(),
-            });
-        } else if use_existing {
-            return Ok(())
+        match try!(q.ensure_channel(channel_name, self.clock)) {
+            ChannelCreation::Created => {
+                self.notify_server(NotifyMessage::ChannelCreate{
+                    queue: q.name().into(),
+                    channel: channel_name.into(),
+                });
+                Ok(())
+            }
+            ChannelCreation::AlreadyExists if use_existing => Ok(()),
+            ChannelCreation::AlreadyExists => Err(QueueError::ChannelAlreadyExists),
         }
-        result
     }
 
     fn notify_server(&self, notification: NotifyMessage) {
@@ -432,7 +485,9 @@ impl Dispatch {
         let q = try_or_int!(self.get_queue(queue_name), 0);
 
         match channel_name {
-            "*" => q.purge(),
+            "*" => {
+                q.purge();
+            }
             channel_name => try_or_int!(q.purge_channel(channel_name, self.clock), 0),
         }
 
@@ -709,13 +764,14 @@ impl Client {
         assert!(self.processing.is_none());
         let cookie = server.make_cookie(self.token);
         self.processing = Some(cookie);
-        // FIXME: this not only allocates but clone 4 ARCs
+        // FIXME: this not only allocates but clone 5 ARCs
         let dispatch = Dispatch {
             cookie: cookie,
             config: server.config.clone(),
             channel: event_loop.channel(),
             request: request,
             meta_lock: server.meta_lock.clone(),
+            disk_quota: server.disk_quota.clone(),
             queues: server.queues.clone(),
             clock: server.clock_s(),
         };
@@ -746,6 +802,7 @@ impl Server {
 
         let mut server = Server {
             listener: listener,
+            disk_quota: Arc::new(DiskQuota::new(config.max_disk_usage)),
             config: Arc::new(config),
             meta_lock: Arc::new(Mutex::new(())),
             queues: Default::default(),
@@ -758,12 +815,14 @@ impl Server {
         };
 
         info!("Opening queues...");
-        let queue_configs = ServerConfig::read_queue_configs(&server.config).
-            expect("Error reading queue configurations");
-        for queue_config in queue_configs {
-            info!("Opening queue {:?}", queue_config.name);
+        for maybe_queue in Queue::discover(&server.config) {
+            let q = match maybe_queue {
+                Ok(q) => q,
+                Err(()) => continue,
+            };
+            info!("Opening queue {:?}", q.name());
 
-            let q = Queue::new(queue_config, true);
+            q.set_disk_quota(server.disk_quota.clone());
             // load state
             let info = q.info(server.clock_s());
             let mut waiting_clients = WaitingClients {
@@ -1014,9 +1073,10 @@ impl Server {
         }
     }
 
-    fn start_maintenance(&mut self, _: &mut EventLoop<ServerHandler>) {
+    fn start_maintenance(&mut self, event_loop: &mut EventLoop<ServerHandler>) {
         let config = self.config.clone();
         let queues = self.queues.clone();
+        let evloop_channel = event_loop.channel();
         self.thread_pool.execute(move || {
             let mut queue_names = Vec::new();
             loop {
@@ -1027,7 +1087,31 @@ impl Server {
                     // get the shared lock for a brief moment
                     let q_opt = queues.read().get(queue_name).cloned();
                     if let Some(q) = q_opt {
-                        q.maintenance(Self::get_clock_s());
+                        let clock = Self::get_clock_s();
+                        if let Err(error) = q.sync_if_due(clock) {
+                            error!("Error syncing queue {:?}: {}", queue_name, error);
+                        }
+                        for channel_name in q.expire_idle_channels(clock) {
+                            evloop_channel.send((
+                                Cookie::new(SERVER, 0),
+                                NotifyMessage::ChannelDelete{
+                                    queue: queue_name.clone(),
+                                    channel: channel_name.into(),
+                                }
+                            )).unwrap();
+                        }
+                        q.maintenance(clock);
+                        q.forward_dead_letters(|target_name, message| {
+                            let target_opt = queues.read().get(target_name).cloned();
+                            if let Some(target) = target_opt {
+                                if let Err(error) = target.push(message, clock) {
+                                    error!("Error pushing dead letter from {:?} to {:?}: {:?}",
+                                        queue_name, target_name, error);
+                                }
+                            } else {
+                                warn!("Dead letter queue {:?} of {:?} not found", target_name, queue_name);
+                            }
+                        });
                     }
                 }
                 thread::sleep(Duration::from_millis(config.maintenance_interval));
@@ -1083,11 +1167,11 @@ impl Server {
 
     fn get_clock_ms() -> u64 {
         let time::Timespec{sec, nsec} = time::get_time();
-        (sec as u64) * 1000 + (nsec / 1_000_000) as u64
+        clamp_monotonic(&LAST_CLOCK_MS, (sec as u64) * 1000 + (nsec / 1_000_000) as u64)
     }
 
     fn get_clock_s() -> u32 {
-        time::get_time().sec as u32
+        (Self::get_clock_ms() / 1000) as u32
     }
 }
 
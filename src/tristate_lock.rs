@@ -59,6 +59,14 @@ impl<T> TristateLock<T>
         }
     }
 
+    /// Consumes the lock, returning the protected data
+    #[inline]
+    #[allow(unused_unsafe)]
+    pub fn into_inner(self) -> T
+    {
+        unsafe { self.data.into_inner() }
+    }
+
     #[inline]
     pub fn read<'a>(&'a self) -> TristateLockReadGuard<'a, T>
     {
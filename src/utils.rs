@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fs;
 use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 pub use std::collections::{HashMap as StdHashMap, HashSet as StdHashSet};
 pub use linked_hash_map::LinkedHashMap as StdLinkedHashMap;
 pub use std::hash::BuildHasherDefault;
@@ -44,6 +45,22 @@ pub fn create_dir_if_not_exist<P: AsRef<Path>>(path: P) -> io::Result<()> {
     }
 }
 
+/// Returns the biggest of value and the previous results, so a clock reading
+/// never goes backwards (eg. NTP adjustments). Otherwise in flight messages
+/// would take longer than expected to expire.
+pub fn clamp_monotonic(last: &AtomicU64, value: u64) -> u64 {
+    let mut prev = last.load(Ordering::Relaxed);
+    loop {
+        if value <= prev {
+            return prev
+        }
+        match last.compare_exchange(prev, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return value,
+            Err(actual) => prev = actual,
+        }
+    }
+}
+
 macro_rules! assert_eq_repr {
     ($left:expr , $right:expr) => ({
         match (format!("{:?}", &$left), format!("{:?}", &$right)) {
@@ -56,3 +73,30 @@ macro_rules! assert_eq_repr {
         }
     })
 }
+
+/// Enters a structured span when built with the `tracing` feature,
+/// the returned guard exits it when dropped
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($name: expr, $($fields: tt)*) => (
+        ::tracing::span!(::tracing::Level::DEBUG, $name, $($fields)*).entered()
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($($tokens: tt)*) => (())
+}
+
+/// Emits a structured event when built with the `tracing` feature
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($($fields: tt)*) => (
+        ::tracing::event!(::tracing::Level::DEBUG, $($fields)*)
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($($tokens: tt)*) => (())
+}